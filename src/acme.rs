@@ -0,0 +1,637 @@
+#![warn(clippy::all)]
+
+use crate::{hosts::HostPattern, http::Vhost, tls::CertResolver};
+use log::{debug, error, info, trace, warn};
+use rcgen::{Certificate, CertificateParams, CustomExtension, KeyPair, PKCS_ECDSA_P256_SHA256};
+use ring::{
+	digest,
+	rand::SystemRandom,
+	signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rustls::{sign, sign::CertifiedKey, ClientConfig, PrivateKey};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::{
+	collections::HashMap,
+	error::Error,
+	fs::{self, File},
+	io::{BufReader, ErrorKind},
+	path::{Path, PathBuf},
+	sync::{
+		mpsc::{self, RecvTimeoutError},
+		Arc, RwLock,
+	},
+	thread,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const HTTP_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Acme {
+	#[serde(default = "default_acme_directory")]
+	pub directory: String,
+
+	#[serde(default)]
+	pub contact: Vec<String>,
+
+	#[serde(default = "default_acme_state_dir")]
+	pub state_dir: PathBuf,
+
+	#[serde(default)]
+	pub ca_roots: Vec<PathBuf>,
+
+	#[serde(default = "default_acme_challenge")]
+	pub challenge: String,
+
+	#[serde(default = "default_acme_renew_before")]
+	pub renew_before: u64,
+
+	#[serde(default = "default_acme_check_interval")]
+	pub check_interval: u64,
+}
+
+impl Default for Acme {
+	fn default() -> Self {
+		Acme {
+			directory: default_acme_directory(),
+			contact: vec![],
+			state_dir: default_acme_state_dir(),
+			ca_roots: vec![],
+			challenge: default_acme_challenge(),
+			renew_before: default_acme_renew_before(),
+			check_interval: default_acme_check_interval(),
+		}
+	}
+}
+
+fn default_acme_directory() -> String {
+	"https://acme-v02.api.letsencrypt.org/directory".to_string()
+}
+
+fn default_acme_state_dir() -> PathBuf {
+	PathBuf::from("acme")
+}
+
+fn default_acme_challenge() -> String {
+	"http-01".to_string()
+}
+
+fn default_acme_renew_before() -> u64 {
+	30
+}
+
+fn default_acme_check_interval() -> u64 {
+	43200
+}
+
+pub fn pemfiles(state_dir: &Path, host: &str) -> Vec<PathBuf> {
+	let dir = state_dir.join(host);
+	vec![dir.join("cert.pem"), dir.join("key.pem")]
+}
+
+pub fn check_names(names: &[String]) -> Result<(), Box<dyn Error>> {
+	for name in names {
+		match HostPattern::parse(name)? {
+			HostPattern::Exact(_) => (),
+			_ => return Err(format!("acme certificates can't be issued for {:?}", name).into()),
+		}
+	}
+
+	Ok(())
+}
+
+fn acme_hosts(vhosts: &[Vhost]) -> Vec<Vec<String>> {
+	vhosts
+		.iter()
+		.filter(|vhost| vhost.tls.as_ref().map(|tls| tls.acme).unwrap_or(false))
+		.map(|vhost| vhost.names().cloned().collect())
+		.collect()
+}
+
+#[derive(Default)]
+pub struct Challenges {
+	http: RwLock<HashMap<String, String>>,
+	tls_alpn: RwLock<HashMap<String, CertifiedKey>>,
+}
+
+impl Challenges {
+	pub fn http(&self, path: &str) -> Option<String> {
+		let token = path.strip_prefix(HTTP_CHALLENGE_PREFIX)?;
+		match self.http.read() {
+			Ok(tokens) => tokens.get(token).cloned(),
+			Err(err) => err.into_inner().get(token).cloned(),
+		}
+	}
+
+	pub fn tls_alpn(&self, host: &str) -> Option<CertifiedKey> {
+		match self.tls_alpn.read() {
+			Ok(keys) => keys.get(host).cloned(),
+			Err(err) => err.into_inner().get(host).cloned(),
+		}
+	}
+
+	fn set_http(&self, token: &str, key_authorization: Option<String>) {
+		let mut tokens = match self.http.write() {
+			Ok(tokens) => tokens,
+			Err(err) => err.into_inner(),
+		};
+		match key_authorization {
+			Some(key_authorization) => tokens.insert(token.to_owned(), key_authorization),
+			None => tokens.remove(token),
+		};
+	}
+
+	fn set_tls_alpn(&self, host: &str, key: Option<CertifiedKey>) {
+		let mut keys = match self.tls_alpn.write() {
+			Ok(keys) => keys,
+			Err(err) => err.into_inner(),
+		};
+		match key {
+			Some(key) => keys.insert(host.to_owned(), key),
+			None => keys.remove(host),
+		};
+	}
+}
+
+fn b64(data: &[u8]) -> String {
+	base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn jwk_thumbprint(jwk: &str) -> String {
+	b64(digest::digest(&digest::SHA256, jwk.as_bytes()).as_ref())
+}
+
+pub fn write_private(path: &Path, data: &str) -> Result<(), Box<dyn Error>> {
+	let tmp = path.with_extension("tmp");
+	fs::write(&tmp, data)?;
+
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::PermissionsExt;
+		fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+	}
+
+	fs::rename(&tmp, path)?;
+	Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+	new_nonce: String,
+	new_account: String,
+	new_order: String,
+}
+
+struct Reply {
+	location: Option<String>,
+	body: String,
+}
+
+impl Reply {
+	fn json(&self) -> Result<Value, Box<dyn Error>> {
+		Ok(serde_json::from_str(&self.body)?)
+	}
+}
+
+struct Client {
+	tls: Arc<ClientConfig>,
+	directory: Directory,
+	key: EcdsaKeyPair,
+	jwk: Value,
+	kid: Option<String>,
+	nonce: Option<String>,
+}
+
+fn check_response(response: ureq::Response) -> Result<ureq::Response, Box<dyn Error>> {
+	if let Some(err) = response.synthetic_error() {
+		return Err(err.to_string().into());
+	}
+	if !response.error() {
+		return Ok(response);
+	}
+
+	let status = response.status();
+	let problem: Value = response.into_json_deserialize().unwrap_or_default();
+	Err(format!(
+		"server returned {} {}: {}",
+		status,
+		problem["type"].as_str().unwrap_or_default(),
+		problem["detail"].as_str().unwrap_or_default()
+	)
+	.into())
+}
+
+fn account_key(state_dir: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+	let path = state_dir.join("account.pem");
+	match fs::read_to_string(&path) {
+		Ok(pem) => Ok(KeyPair::from_pem(&pem)?.serialize_der()),
+		Err(err) if err.kind() == ErrorKind::NotFound => {
+			info!("Generating ACME account key {:?}", &path);
+			let key = KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?;
+			fs::create_dir_all(state_dir)?;
+			write_private(&path, &key.serialize_pem())?;
+			Ok(key.serialize_der())
+		}
+		Err(err) => Err(err.into()),
+	}
+}
+
+impl Client {
+	fn new(config: &Acme) -> Result<Self, Box<dyn Error>> {
+		let mut tls = ClientConfig::new();
+		tls.root_store
+			.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+		for root in &config.ca_roots {
+			tls.root_store
+				.add_pem_file(&mut BufReader::new(File::open(root)?))
+				.map_err(|_| format!("{:?}: unable to parse certificates", root))?;
+		}
+		let tls = Arc::new(tls);
+
+		debug!("fetching acme directory {:?}", &config.directory);
+		let directory = check_response(
+			ureq::get(&config.directory)
+				.set_tls_config(tls.to_owned())
+				.timeout(Duration::from_secs(30))
+				.call(),
+		)?
+		.into_json_deserialize()?;
+
+		let key = EcdsaKeyPair::from_pkcs8(
+			&ECDSA_P256_SHA256_FIXED_SIGNING,
+			&account_key(&config.state_dir)?,
+		)
+		.map_err(|err| format!("unable to parse account key: {}", err))?;
+		let public = key.public_key().as_ref();
+		let jwk = json!({
+			"crv": "P-256",
+			"kty": "EC",
+			"x": b64(&public[1..33]),
+			"y": b64(&public[33..65]),
+		});
+
+		let mut client = Client {
+			tls,
+			directory,
+			key,
+			jwk,
+			kid: None,
+			nonce: None,
+		};
+
+		let account = client.post(
+			&client.directory.new_account.to_owned(),
+			Some(json!({
+				"termsOfServiceAgreed": true,
+				"contact": config.contact,
+			})),
+		)?;
+		client.kid = Some(account.location.ok_or("no account url returned")?);
+		debug!("using acme account {:?}", client.kid);
+
+		Ok(client)
+	}
+
+	fn thumbprint(&self) -> String {
+		// RFC 7638 requires the members in lexicographic order, so they're written out by hand.
+		let public = self.key.public_key().as_ref();
+		jwk_thumbprint(&format!(
+			r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+			b64(&public[1..33]),
+			b64(&public[33..65])
+		))
+	}
+
+	fn nonce(&mut self) -> Result<String, Box<dyn Error>> {
+		if let Some(nonce) = self.nonce.take() {
+			return Ok(nonce);
+		}
+
+		let response = check_response(
+			ureq::head(&self.directory.new_nonce)
+				.set_tls_config(self.tls.to_owned())
+				.timeout(Duration::from_secs(30))
+				.call(),
+		)?;
+		Ok(response
+			.header("Replay-Nonce")
+			.ok_or("no nonce returned")?
+			.to_owned())
+	}
+
+	fn sign(&mut self, url: &str, payload: &Option<Value>) -> Result<String, Box<dyn Error>> {
+		let mut protected = json!({
+			"alg": "ES256",
+			"nonce": self.nonce()?,
+			"url": url,
+		});
+		match &self.kid {
+			Some(kid) => protected["kid"] = json!(kid),
+			None => protected["jwk"] = self.jwk.to_owned(),
+		}
+
+		let protected = b64(protected.to_string().as_bytes());
+		let payload = match payload {
+			Some(payload) => b64(payload.to_string().as_bytes()),
+			None => String::new(),
+		};
+		let signature = self
+			.key
+			.sign(
+				&SystemRandom::new(),
+				[&protected, ".", &payload].concat().as_bytes(),
+			)
+			.map_err(|_| "unable to sign request")?;
+
+		Ok(json!({
+			"protected": protected,
+			"payload": payload,
+			"signature": b64(signature.as_ref()),
+		})
+		.to_string())
+	}
+
+	fn post(&mut self, url: &str, payload: Option<Value>) -> Result<Reply, Box<dyn Error>> {
+		trace!("sending acme request to {:?}", url);
+
+		let mut retries = 3;
+		loop {
+			let response = ureq::post(url)
+				.set("Content-Type", "application/jose+json")
+				.set_tls_config(self.tls.to_owned())
+				.timeout(Duration::from_secs(30))
+				.send_string(&self.sign(url, &payload)?);
+			self.nonce = response.header("Replay-Nonce").map(str::to_owned);
+
+			match check_response(response) {
+				Ok(response) => {
+					return Ok(Reply {
+						location: response.header("Location").map(str::to_owned),
+						body: response.into_string()?,
+					})
+				}
+				Err(err) if retries > 0 && err.to_string().contains(":badNonce") => {
+					debug!("acme server rejected nonce, retrying");
+					retries -= 1;
+				}
+				Err(err) => return Err(err),
+			}
+		}
+	}
+
+	fn poll(&mut self, url: &str, pending: &[&str]) -> Result<Value, Box<dyn Error>> {
+		for _ in 0..60 {
+			let object = self.post(url, None)?.json()?;
+			let status = object["status"].as_str().unwrap_or_default();
+			if !pending.contains(&status) {
+				return Ok(object);
+			}
+
+			trace!("waiting for {:?} to leave {:?} state", url, status);
+			thread::sleep(Duration::from_secs(2));
+		}
+
+		Err(format!("timed out waiting for {:?}", url).into())
+	}
+
+	fn authorize(
+		&mut self,
+		url: &str,
+		config: &Acme,
+		challenges: &Challenges,
+	) -> Result<(), Box<dyn Error>> {
+		let authz = self.post(url, None)?.json()?;
+		let host = authz["identifier"]["value"].as_str().unwrap_or_default();
+		if authz["status"] == "valid" {
+			debug!("acme authorization for {:?} is already valid", host);
+			return Ok(());
+		}
+
+		let challenge = authz["challenges"]
+			.as_array()
+			.and_then(|list| list.iter().find(|item| item["type"] == *config.challenge))
+			.ok_or(format!(
+				"server doesn't offer {} challenges",
+				config.challenge
+			))?;
+		let token = challenge["token"]
+			.as_str()
+			.ok_or("challenge has no token")?;
+		let challenge_url = challenge["url"].as_str().ok_or("challenge has no url")?;
+		let key_authorization = [token, ".", &self.thumbprint()].concat();
+
+		debug!(
+			"responding to {} challenge for {:?}",
+			config.challenge, host
+		);
+		match config.challenge.as_ref() {
+			"tls-alpn-01" => {
+				let digest = digest::digest(&digest::SHA256, key_authorization.as_bytes());
+				let mut params = CertificateParams::new(vec![host.to_owned()]);
+				params.custom_extensions =
+					vec![CustomExtension::new_acme_identifier(digest.as_ref())];
+				let cert = Certificate::from_params(params)?;
+				let key = sign::any_supported_type(&PrivateKey(cert.serialize_private_key_der()))
+					.map_err(|_| "unable to parse challenge key")?;
+				challenges.set_tls_alpn(
+					host,
+					Some(CertifiedKey::new(
+						vec![rustls::Certificate(cert.serialize_der()?)],
+						Arc::new(key),
+					)),
+				);
+			}
+			_ => challenges.set_http(token, Some(key_authorization)),
+		}
+
+		let result = self
+			.post(challenge_url, Some(json!({})))
+			.and_then(|_| self.poll(url, &["pending", "processing"]));
+
+		match config.challenge.as_ref() {
+			"tls-alpn-01" => challenges.set_tls_alpn(host, None),
+			_ => challenges.set_http(token, None),
+		}
+
+		let authz = result?;
+		match authz["status"].as_str() {
+			Some("valid") => Ok(()),
+			status => Err(format!(
+				"authorization for {:?} is {}: {}",
+				host,
+				status.unwrap_or("unknown"),
+				authz["challenges"]
+			)
+			.into()),
+		}
+	}
+
+	fn order(
+		&mut self,
+		names: &[String],
+		config: &Acme,
+		challenges: &Challenges,
+	) -> Result<(String, String), Box<dyn Error>> {
+		let identifiers = names
+			.iter()
+			.map(|name| json!({"type": "dns", "value": name}))
+			.collect::<Vec<_>>();
+		let reply = self.post(
+			&self.directory.new_order.to_owned(),
+			Some(json!({ "identifiers": identifiers })),
+		)?;
+		let order_url = reply.location.to_owned().ok_or("no order url returned")?;
+		let order = reply.json()?;
+
+		for authz in order["authorizations"].as_array().into_iter().flatten() {
+			self.authorize(
+				authz.as_str().ok_or("invalid authorization url")?,
+				config,
+				challenges,
+			)?;
+		}
+
+		let order = self.poll(&order_url, &["pending"])?;
+		if order["status"] != "ready" && order["status"] != "valid" {
+			return Err(format!("order is {}", order["status"]).into());
+		}
+
+		let cert = Certificate::from_params(CertificateParams::new(names.to_owned()))?;
+		if order["status"] == "ready" {
+			let finalize = order["finalize"]
+				.as_str()
+				.ok_or("order has no finalize url")?;
+			self.post(
+				finalize,
+				Some(json!({ "csr": b64(&cert.serialize_request_der()?) })),
+			)?;
+		}
+
+		let order = self.poll(&order_url, &["ready", "processing"])?;
+		let certificate = match order["status"].as_str() {
+			Some("valid") => order["certificate"]
+				.as_str()
+				.ok_or("order has no certificate url")?,
+			_ => return Err(format!("order is {}", order["status"]).into()),
+		};
+
+		let chain = self.post(certificate, None)?.body;
+		Ok((chain, cert.serialize_private_key_pem()))
+	}
+}
+
+fn needs_renewal(names: &[String], config: &Acme, resolver: &CertResolver) -> bool {
+	let host = &names[0];
+	if !resolver.covers(host, names) {
+		return true;
+	}
+
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|now| now.as_secs() as i64)
+		.unwrap_or_default();
+
+	match resolver.expiry(host) {
+		Some(not_after) => not_after - now <= config.renew_before as i64 * 86400,
+		None => true,
+	}
+}
+
+fn renew_certs(
+	hosts: &[Vec<String>],
+	config: &Acme,
+	resolver: &CertResolver,
+	challenges: &Challenges,
+) {
+	let mut client = None;
+	for names in hosts {
+		let host = &names[0];
+		if !needs_renewal(names, config, resolver) {
+			trace!("acme certificate for {:?} doesn't need renewal", host);
+			continue;
+		}
+		info!("Requesting ACME certificate for {:?}", host);
+
+		let result = (|| {
+			if client.is_none() {
+				client = Some(Client::new(config)?);
+			}
+			let (chain, key) = client
+				.as_mut()
+				.ok_or("no acme client")?
+				.order(names, config, challenges)?;
+
+			let pemfiles = pemfiles(&config.state_dir, host);
+			fs::create_dir_all(config.state_dir.join(host))?;
+			write_private(&pemfiles[1], &key)?;
+			write_private(&pemfiles[0], &chain)?;
+			resolver.load(names, &pemfiles)
+		})();
+
+		if let Err(err) = result {
+			error!("Unable to obtain ACME certificate for {:?}! {}", host, err);
+		}
+	}
+}
+
+pub struct Manager(mpsc::Sender<Vec<Vec<String>>>);
+
+impl Manager {
+	pub fn start(
+		config: &Acme,
+		vhosts: &[Vhost],
+		resolver: Arc<CertResolver>,
+		challenges: Arc<Challenges>,
+	) -> Result<Self, Box<dyn Error>> {
+		match config.challenge.as_ref() {
+			"http-01" | "tls-alpn-01" => (),
+			challenge => return Err(format!("unknown acme challenge {:?}", challenge).into()),
+		}
+
+		let config = config.to_owned();
+		let mut hosts = acme_hosts(vhosts);
+		let (sender, receiver) = mpsc::channel();
+
+		thread::Builder::new()
+			.name("acme".to_string())
+			.spawn(move || loop {
+				renew_certs(&hosts, &config, &resolver, &challenges);
+
+				match receiver.recv_timeout(Duration::from_secs(config.check_interval.max(60))) {
+					Ok(updated) => hosts = updated,
+					Err(RecvTimeoutError::Timeout) => (),
+					Err(RecvTimeoutError::Disconnected) => return,
+				}
+			})?;
+
+		Ok(Manager(sender))
+	}
+
+	pub fn update(&self, vhosts: &[Vhost]) {
+		if self.0.send(acme_hosts(vhosts)).is_err() {
+			warn!("ACME client has stopped, unable to update hosts");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn jwk_thumbprint_matches_rfc_7638() {
+		// The example from RFC 7638 section 3.1.
+		let jwk = concat!(
+			r#"{"e":"AQAB","kty":"RSA","n":"0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W"#,
+			r#"-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw"}"#
+		);
+		assert_eq!(
+			jwk_thumbprint(jwk),
+			"NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+		);
+	}
+}
@@ -0,0 +1,345 @@
+#![warn(clippy::all)]
+
+use crate::html::{self, Token};
+use liquid_core::{
+	model::ScalarCow, Display_filter, Filter, FilterReflection, ParseFilter, Result, Runtime,
+	Value, ValueView,
+};
+use ring::digest;
+use serde_derive::Deserialize;
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	path::{Path, PathBuf},
+};
+
+pub type Manifest = BTreeMap<String, String>;
+
+#[derive(Default, Clone, Debug)]
+pub struct InlineHashes {
+	pub scripts: BTreeSet<String>,
+	pub styles: BTreeSet<String>,
+}
+
+impl InlineHashes {
+	pub fn extend(&mut self, other: InlineHashes) {
+		self.scripts.extend(other.scripts);
+		self.styles.extend(other.styles);
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Assets {
+	#[serde(default = "default_assets_extensions")]
+	pub extensions: Vec<String>,
+
+	#[serde(default = "default_assets_manifest")]
+	pub manifest: PathBuf,
+
+	#[serde(default = "default_assets_hash_length")]
+	pub hash_length: usize,
+}
+
+fn default_assets_extensions() -> Vec<String> {
+	vec!["css".to_string(), "js".to_string()]
+}
+
+fn default_assets_manifest() -> PathBuf {
+	PathBuf::from("assets.json")
+}
+
+fn default_assets_hash_length() -> usize {
+	8
+}
+
+impl Default for Assets {
+	fn default() -> Self {
+		Assets {
+			extensions: default_assets_extensions(),
+			manifest: default_assets_manifest(),
+			hash_length: default_assets_hash_length(),
+		}
+	}
+}
+
+impl Assets {
+	pub fn matches(&self, path: &Path) -> bool {
+		match path.extension().unwrap_or_default().to_str() {
+			Some(ext) => self.extensions.iter().any(|e| e == ext),
+			None => false,
+		}
+	}
+
+	pub fn fingerprint(&self, path: &Path, content: &[u8]) -> PathBuf {
+		let hash = digest::digest(&digest::SHA256, content)
+			.as_ref()
+			.iter()
+			.map(|byte| format!("{:02x}", byte))
+			.collect::<String>();
+		let hash = &hash[..self.hash_length.min(hash.len())];
+
+		let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+		match path.extension() {
+			Some(ext) => {
+				path.with_file_name([&stem, ".", hash, ".", &ext.to_string_lossy()].concat())
+			}
+			None => path.with_file_name([&stem, ".", hash].concat()),
+		}
+	}
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Csp {
+	#[serde(default)]
+	pub policy: String,
+
+	pub output: Option<PathBuf>,
+
+	#[serde(default)]
+	pub meta: bool,
+}
+
+impl Csp {
+	pub fn render_policy(&self, hashes: &InlineHashes) -> String {
+		let mut directives = self
+			.policy
+			.split(';')
+			.map(str::trim)
+			.filter(|directive| !directive.is_empty())
+			.map(|directive| {
+				let mut values = directive.split_whitespace();
+				let name = values.next().unwrap_or_default().to_ascii_lowercase();
+				(name, values.map(String::from).collect::<Vec<_>>())
+			})
+			.collect::<Vec<_>>();
+
+		let default_src = directives
+			.iter()
+			.find(|(name, _)| name == "default-src")
+			.map(|(_, values)| values.to_owned())
+			.unwrap_or_else(|| vec!["'self'".to_string()]);
+
+		for (directive, sources) in &[
+			("script-src", &hashes.scripts),
+			("style-src", &hashes.styles),
+		] {
+			if sources.is_empty() {
+				continue;
+			}
+
+			let index = match directives.iter().position(|(name, _)| name == directive) {
+				Some(index) => index,
+				None => {
+					directives.push((directive.to_string(), default_src.to_owned()));
+					directives.len() - 1
+				}
+			};
+			let values = &mut directives[index].1;
+			values.retain(|value| value != "'none'");
+			values.extend(sources.iter().map(|hash| ["'", hash, "'"].concat()));
+		}
+
+		directives
+			.iter()
+			.map(|(name, values)| {
+				if values.is_empty() {
+					name.to_owned()
+				} else {
+					[name.as_str(), " ", &values.join(" ")].concat()
+				}
+			})
+			.collect::<Vec<_>>()
+			.join("; ")
+	}
+
+	pub fn insert_meta(&self, input: &str, policy: &str) -> String {
+		let lowercase = input.to_ascii_lowercase();
+		let index = ["<head", "<html", "<!doctype"]
+			.iter()
+			.filter_map(|tag| {
+				let start = lowercase.match_indices(tag).map(|(i, _)| i).find(|i| {
+					lowercase[i + tag.len()..]
+						.starts_with(|c: char| c == '>' || c.is_ascii_whitespace())
+				})?;
+				Some(start + lowercase[start..].find('>')? + 1)
+			})
+			.next()
+			.unwrap_or(0);
+
+		[
+			&input[..index],
+			"<meta http-equiv=Content-Security-Policy content=\"",
+			&policy.replace('"', "&quot;"),
+			"\">",
+			&input[index..],
+		]
+		.concat()
+	}
+}
+
+pub fn integrity(content: &[u8]) -> String {
+	[
+		"sha384-",
+		&base64::encode(digest::digest(&digest::SHA384, content)),
+	]
+	.concat()
+}
+
+fn inline_hash(content: &str) -> String {
+	[
+		"sha256-",
+		&base64::encode(digest::digest(&digest::SHA256, content.as_bytes())),
+	]
+	.concat()
+}
+
+pub fn inline_hashes(input: &str) -> InlineHashes {
+	let mut hashes = InlineHashes::default();
+
+	let mut tokens = html::tokenize(input).into_iter().peekable();
+	while let Some(token) = tokens.next() {
+		let is_script = match &token {
+			Token::Start { name, .. } if name == "script" && token.attr("src").is_none() => true,
+			Token::Start { name, .. } if name == "style" => false,
+			_ => continue,
+		};
+
+		if let Some(Token::Text(content)) = tokens.peek() {
+			match is_script {
+				true => hashes.scripts.insert(inline_hash(content)),
+				false => hashes.styles.insert(inline_hash(content)),
+			};
+		}
+	}
+
+	hashes
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+	name = "asset_url",
+	description = "Converts an asset's filename into its fingerprinted filename.",
+	parsed(AssetUrlFilter)
+)]
+pub struct AssetUrl;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "asset_url"]
+struct AssetUrlFilter;
+
+impl Filter for AssetUrlFilter {
+	fn evaluate(&self, input: &dyn ValueView, runtime: &dyn Runtime) -> Result<Value> {
+		let name = input.to_kstr();
+		let path = [
+			ScalarCow::new("site"),
+			ScalarCow::new("assets"),
+			ScalarCow::new(name.as_str()),
+		];
+
+		match runtime.try_get(&path) {
+			Some(asset) => Ok(asset.to_value()),
+			None => Ok(Value::scalar(name.into_owned())),
+		}
+	}
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+	name = "integrity",
+	description = "Returns the Subresource Integrity hash of an asset.",
+	parsed(IntegrityFilter)
+)]
+pub struct Integrity;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "integrity"]
+struct IntegrityFilter;
+
+impl Filter for IntegrityFilter {
+	fn evaluate(&self, input: &dyn ValueView, runtime: &dyn Runtime) -> Result<Value> {
+		let name = input.to_kstr();
+		let path = [
+			ScalarCow::new("site"),
+			ScalarCow::new("integrity"),
+			ScalarCow::new(name.as_str()),
+		];
+
+		match runtime.try_get(&path) {
+			Some(hash) => Ok(hash.to_value()),
+			None => Ok(Value::Nil),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn csp(policy: &str) -> Csp {
+		Csp {
+			policy: policy.to_string(),
+			..Csp::default()
+		}
+	}
+
+	#[test]
+	fn inline_hashes_skip_external_and_empty_elements() {
+		let hashes = inline_hashes(concat!(
+			"<script>alert(1)</script>",
+			"<script src=app.js>ignored()</script>",
+			"<script></script>",
+			"<style>p{color:red}</style>",
+			"<style></style>",
+		));
+
+		assert_eq!(
+			hashes.scripts.into_iter().collect::<Vec<_>>(),
+			vec![inline_hash("alert(1)")]
+		);
+		assert_eq!(
+			hashes.styles.into_iter().collect::<Vec<_>>(),
+			vec![inline_hash("p{color:red}")]
+		);
+	}
+
+	#[test]
+	fn policy_hashes_are_added_to_existing_directives() {
+		let hashes = inline_hashes("<script>alert(1)</script>");
+
+		assert_eq!(
+			csp("default-src 'none'; Script-Src 'none'").render_policy(&hashes),
+			format!(
+				"default-src 'none'; script-src '{}'",
+				inline_hash("alert(1)")
+			)
+		);
+	}
+
+	#[test]
+	fn policy_directives_are_created_from_default_src() {
+		let hashes = inline_hashes("<style>p{color:red}</style>");
+
+		assert_eq!(
+			csp("default-src 'self' https:; img-src *").render_policy(&hashes),
+			format!(
+				"default-src 'self' https:; img-src *; style-src 'self' https: '{}'",
+				inline_hash("p{color:red}")
+			)
+		);
+		assert_eq!(
+			csp("").render_policy(&hashes),
+			format!("style-src 'self' '{}'", inline_hash("p{color:red}"))
+		);
+	}
+
+	#[test]
+	fn policy_is_unchanged_without_hashes() {
+		let hashes = inline_hashes("<script></script><style></style>");
+
+		assert_eq!(
+			csp("default-src 'none'; upgrade-insecure-requests;").render_policy(&hashes),
+			"default-src 'none'; upgrade-insecure-requests"
+		);
+	}
+}
@@ -1,12 +1,25 @@
 #![warn(clippy::all)]
 
-use comrak::ComrakOptions;
+use crate::{
+	assets::{self, Assets, Csp, InlineHashes},
+	html::{self, Token},
+	i18n::{self, I18n},
+	images::{self, Images},
+	linkchecker::{self, LinkChecker},
+	markdown::{self, Markdown, WikiTargets},
+	plugins::{Plugin, PluginConfig, Plugins, Transform},
+	related::Related,
+	runner::{self, ExternalCommand},
+	shortcodes::{self, Shortcode},
+};
 use extract_frontmatter::Extractor;
 use grass::{Options, OutputStyle};
+use lightningcss::stylesheet::{MinifyOptions, ParserOptions, PrinterOptions, StyleSheet};
 use liquid::{
 	model::Value,
 	partials::{InMemorySource, LazyCompiler},
-	Object, ParserBuilder,
+	reflection::ParserReflection,
+	Object, Parser, ParserBuilder,
 };
 use log::{debug, error, info, trace, warn};
 use rayon::prelude::*;
@@ -24,6 +37,20 @@ struct Site {
 	pages: Vec<Page>,
 	files: Vec<PathBuf>,
 	data: Vec<Object>,
+	assets: assets::Manifest,
+	integrity: assets::Manifest,
+	images: images::Manifest,
+	languages: Vec<String>,
+	i18n: Object,
+}
+
+impl Site {
+	fn add_assets(&mut self, assets: Vec<RenderedAsset>) {
+		for (fingerprinted, integrity) in assets {
+			self.assets.extend(fingerprinted);
+			self.integrity.extend(integrity);
+		}
+	}
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -31,6 +58,50 @@ struct Page {
 	path: PathBuf,
 	data: Object,
 	content: String,
+
+	#[serde(skip)]
+	source: PathBuf,
+
+	#[serde(skip)]
+	shortcodes: Vec<String>,
+
+	#[serde(skip)]
+	links: Vec<PathBuf>,
+
+	backlinks: Vec<PageRef>,
+	related: Vec<PageRef>,
+	translations: Vec<Translation>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct PageRef {
+	path: PathBuf,
+	title: String,
+}
+
+impl PageRef {
+	fn new(page: &Page, builder: &Builder, plugins: &Plugins) -> Self {
+		let path = output_path(page, builder, plugins);
+		PageRef {
+			title: page_title(page).unwrap_or_else(|| path.to_string_lossy().to_string()),
+			path,
+		}
+	}
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct Translation {
+	lang: String,
+	path: PathBuf,
+	title: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct SearchEntry {
+	url: PathBuf,
+	title: String,
+	headings: Vec<String>,
+	text: String,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -47,8 +118,52 @@ pub struct Builder {
 
 	#[serde(default)]
 	pub default_vars: Object,
+
+	#[serde(default)]
+	pub scoped_vars: Vec<ScopedVars>,
+
+	#[serde(default)]
+	pub commands: Vec<ExternalCommand>,
+
+	#[serde(default)]
+	pub plugins: Vec<PluginConfig>,
+
+	#[serde(default)]
+	pub markdown: Markdown,
+
+	#[serde(default)]
+	pub i18n: I18n,
+
+	#[serde(default)]
+	pub related: Related,
+
+	#[serde(default)]
+	pub search: Search,
+
+	#[serde(default)]
+	pub assets: Assets,
+
+	#[serde(default)]
+	pub csp: Csp,
+
+	#[serde(default)]
+	pub images: Images,
+
+	#[serde(default)]
+	pub link_checker: LinkChecker,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ScopedVars {
+	pub path: String,
+
+	#[serde(default)]
+	pub vars: Object,
 }
 
+const DEFAULTS_FILE: &str = "_defaults.toml";
+
 #[derive(Deserialize, Clone, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Dirs {
@@ -60,6 +175,12 @@ pub struct Dirs {
 
 	#[serde(default = "default_include_dir")]
 	pub include_dir: PathBuf,
+
+	#[serde(default = "default_shortcode_dir")]
+	pub shortcode_dir: PathBuf,
+
+	#[serde(default = "default_i18n_dir")]
+	pub i18n_dir: PathBuf,
 }
 
 fn default_data_dir() -> PathBuf {
@@ -74,12 +195,22 @@ fn default_include_dir() -> PathBuf {
 	PathBuf::from("_includes")
 }
 
+fn default_shortcode_dir() -> PathBuf {
+	PathBuf::from("_shortcodes")
+}
+
+fn default_i18n_dir() -> PathBuf {
+	PathBuf::from("_i18n")
+}
+
 impl Default for Dirs {
 	fn default() -> Self {
 		Dirs {
 			data_dir: default_data_dir(),
 			layout_dir: default_layout_dir(),
 			include_dir: default_include_dir(),
+			shortcode_dir: default_shortcode_dir(),
+			i18n_dir: default_i18n_dir(),
 		}
 	}
 }
@@ -93,17 +224,47 @@ pub struct Renderers {
 	#[serde(default)]
 	pub liquid: bool,
 
+	#[serde(default)]
+	pub shortcodes: bool,
+
 	#[serde(default)]
 	pub sass: bool,
 
 	#[serde(default)]
 	pub markdown: bool,
 
+	#[serde(default)]
+	pub commands: bool,
+
+	#[serde(default)]
+	pub plugins: bool,
+
 	#[serde(default)]
 	pub sanitizer: bool,
 
 	#[serde(default)]
 	pub layout: bool,
+
+	#[serde(default)]
+	pub related: bool,
+
+	#[serde(default)]
+	pub search: bool,
+
+	#[serde(default)]
+	pub minify: bool,
+
+	#[serde(default)]
+	pub images: bool,
+
+	#[serde(default)]
+	pub fingerprint: bool,
+
+	#[serde(default)]
+	pub integrity: bool,
+
+	#[serde(default)]
+	pub link_checker: bool,
 }
 
 impl Default for Renderers {
@@ -111,10 +272,47 @@ impl Default for Renderers {
 		Renderers {
 			data: true,
 			liquid: true,
+			shortcodes: false,
 			sass: true,
 			markdown: true,
+			commands: true,
+			plugins: true,
 			sanitizer: false,
 			layout: true,
+			related: false,
+			search: false,
+			minify: false,
+			images: false,
+			fingerprint: false,
+			integrity: false,
+			link_checker: false,
+		}
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Search {
+	#[serde(default = "default_search_output")]
+	pub output: PathBuf,
+
+	#[serde(default = "default_search_text_length")]
+	pub text_length: usize,
+}
+
+fn default_search_output() -> PathBuf {
+	PathBuf::from("search.json")
+}
+
+fn default_search_text_length() -> usize {
+	5000
+}
+
+impl Default for Search {
+	fn default() -> Self {
+		Search {
+			output: default_search_output(),
+			text_length: default_search_text_length(),
 		}
 	}
 }
@@ -127,7 +325,7 @@ fn read_data(input: PathBuf) -> Option<Object> {
 
 	match fs::read_to_string(&input) {
 		Ok(text) => match toml::from_str(&text) {
-			Ok(obj) => obj,
+			Ok(obj) => Some(obj),
 			Err(err) => {
 				warn!("Unable to parse {:?}! {}", &input, err);
 				None
@@ -173,6 +371,12 @@ fn create_page(input: PathBuf, defaults: &Object, renderers: &Renderers) -> Opti
 		path: PathBuf::from(input.file_name().unwrap_or_default()),
 		data: defaults.to_owned(),
 		content: input_str,
+		source: PathBuf::from(input.file_name().unwrap_or_default()),
+		shortcodes: vec![],
+		links: vec![],
+		backlinks: vec![],
+		related: vec![],
+		translations: vec![],
 	};
 
 	let mut extractor = Extractor::new(&page.content);
@@ -198,29 +402,68 @@ fn create_page(input: PathBuf, defaults: &Object, renderers: &Renderers) -> Opti
 	Some(page)
 }
 
-fn render_markdown(input: &str, renderers: &Renderers) -> String {
-	let mut options = ComrakOptions::default();
-	options.extension.strikethrough = true;
-	options.extension.table = true;
-	options.extension.autolink = true;
-	options.extension.tasklist = true;
-	options.extension.superscript = true;
-	if !renderers.sanitizer {
-		options.extension.header_ids = Some("".to_string());
-		options.extension.footnotes = true;
+fn render_sass(input: String) -> Result<String, Box<grass::Error>> {
+	let options = Options::default().style(OutputStyle::Compressed);
+
+	grass::from_string(input, &options)
+}
+
+fn render_minified(path: &Path, input: &str) -> Result<Option<String>, String> {
+	match path.extension().unwrap_or_default().to_str() {
+		Some("html") | Some("htm") => {
+			let mut cfg = minify_html::Cfg::spec_compliant();
+			cfg.minify_css = true;
+			cfg.minify_js = true;
+
+			Ok(Some(
+				String::from_utf8_lossy(&minify_html::minify(input.as_bytes(), &cfg)).to_string(),
+			))
+		}
+		Some("css") => StyleSheet::parse(input, ParserOptions::default())
+			.map_err(|err| err.to_string())
+			.and_then(|mut sheet| {
+				sheet
+					.minify(MinifyOptions::default())
+					.map_err(|err| err.to_string())?;
+				sheet
+					.to_css(PrinterOptions {
+						minify: true,
+						..PrinterOptions::default()
+					})
+					.map(|css| Some(css.code))
+					.map_err(|err| err.to_string())
+			}),
+		Some("js") | Some("mjs") => {
+			let mode = match path.extension().unwrap_or_default().to_str() {
+				Some("mjs") => minify_js::TopLevelMode::Module,
+				_ => minify_js::TopLevelMode::Global,
+			};
+			let session = minify_js::Session::new();
+			let mut output = Vec::new();
+
+			minify_js::minify(&session, mode, input.as_bytes(), &mut output)
+				.map(|_| Some(String::from_utf8_lossy(&output).to_string()))
+				.map_err(|err| err.to_string())
+		}
+		_ => Ok(None),
 	}
-	options.extension.description_lists = true;
-	options.extension.front_matter_delimiter = Some("---".to_string());
-	options.parse.smart = true;
-	options.render.unsafe_ = true;
+}
 
-	comrak::markdown_to_html(input, &options)
+fn liquid_builtins() -> ParserBuilder {
+	ParserBuilder::with_stdlib()
+		.filter(assets::AssetUrl)
+		.filter(assets::Integrity)
+		.filter(images::Picture)
+		.filter(i18n::Translate)
 }
 
-fn render_sass(input: String) -> Result<String, Box<grass::Error>> {
-	let options = Options::default().style(OutputStyle::Compressed);
+fn liquid_parser(partials: InMemorySource, plugins: &Plugins) -> Result<Parser, liquid::Error> {
+	let mut parser = liquid_builtins();
+	for filter in plugins.filters() {
+		parser = parser.filter(filter);
+	}
 
-	grass::from_string(input, &options)
+	parser.partials(LazyCompiler::new(partials)).build()
 }
 
 fn render_liquid(
@@ -228,14 +471,30 @@ fn render_liquid(
 	page: &Page,
 	site: &Site,
 	partials: InMemorySource,
+	plugins: &Plugins,
 ) -> Result<String, liquid::Error> {
-	ParserBuilder::with_stdlib()
-		.partials(LazyCompiler::new(partials))
-		.build()?
+	liquid_parser(partials, plugins)?
+		.parse(raw_template)?
+		.render(&liquid::object!({
+				"site": site,
+				"page": page,
+		}))
+}
+
+fn render_shortcode(
+	raw_template: &str,
+	shortcode: &Shortcode,
+	page: &Page,
+	site: &Site,
+	partials: InMemorySource,
+	plugins: &Plugins,
+) -> Result<String, liquid::Error> {
+	liquid_parser(partials, plugins)?
 		.parse(raw_template)?
 		.render(&liquid::object!({
 				"site": site,
 				"page": page,
+				"shortcode": shortcode,
 		}))
 }
 
@@ -243,25 +502,82 @@ fn build_site_page(
 	mut page: Page,
 	site: Site,
 	renderers: &Renderers,
+	command: Option<&ExternalCommand>,
 	partials: InMemorySource,
+	templates: &shortcodes::Templates,
+	plugins: &Plugins,
 ) -> Page {
-	if renderers.liquid {
-		debug!("building {:?}", &page.path);
+	if renderers.shortcodes {
+		let (content, rendered) = shortcodes::extract(&page.content, |shortcode| {
+			trace!(
+				"rendering shortcode {:?} in {:?}",
+				&shortcode.name,
+				&page.path
+			);
 
-		page.content = render_liquid(&page.content, &page, &site, partials).unwrap_or_else(|err| {
+			let template = templates
+				.get(&shortcode.name)
+				.ok_or_else(|| format!("unknown shortcode {:?}", &shortcode.name))?;
+			render_shortcode(
+				template,
+				shortcode,
+				&page,
+				&site,
+				partials.to_owned(),
+				plugins,
+			)
+			.map_err(|err| format!("shortcode {:?}: {}", &shortcode.name, err))
+		})
+		.unwrap_or_else(|err| {
 			error!("Unable to build {:?}! {}", &page.path, err);
 			process::exit(exitcode::DATAERR);
-		})
+		});
+
+		page.content = content;
+		page.shortcodes = rendered;
+	}
+
+	if renderers.liquid && command.is_none_or(|command| command.liquid) {
+		debug!("building {:?}", &page.path);
+
+		page.content =
+			render_liquid(&page.content, &page, &site, partials, plugins).unwrap_or_else(|err| {
+				error!("Unable to build {:?}! {}", &page.path, err);
+				process::exit(exitcode::DATAERR);
+			})
 	};
 
 	page
 }
 
-fn render_page(mut page: Page, renderers: &Renderers) -> Page {
+fn render_page(
+	mut page: Page,
+	builder: &Builder,
+	plugins: &Plugins,
+	targets: &WikiTargets,
+) -> Page {
+	let renderers = &builder.renderers;
+
 	match page.path.as_path().extension().unwrap_or_default().to_str() {
 		Some("md") if renderers.markdown => {
 			debug!("generating {:?}", &page.path);
-			page.content = render_markdown(&page.content, renderers);
+			let (content, links) = markdown::render_markdown(
+				&page.content,
+				&builder.markdown,
+				renderers.sanitizer,
+				&builder.input_dir,
+				targets,
+			);
+			page.content = content;
+			for link in links {
+				match link.url {
+					Some(url) => page.links.push(url),
+					None => warn!(
+						"Unable to resolve wiki link {:?} in {:?}!",
+						&link.target, &page.source
+					),
+				}
+			}
 			page.path.set_extension("html");
 		}
 		Some("scss") if renderers.sass => {
@@ -272,6 +588,49 @@ fn render_page(mut page: Page, renderers: &Renderers) -> Page {
 			});
 			page.path.set_extension("css");
 		}
+		_ => {
+			if let Some((plugin, transform)) = plugin_transform(&page, builder, plugins) {
+				debug!("generating {:?} with plugin {:?}", &page.path, &plugin.name);
+				page.content = plugin
+					.call_str(&transform.function, &page.content)
+					.unwrap_or_else(|err| {
+						error!("Unable to generate {:?}! {}", &page.path, err);
+						process::exit(exitcode::DATAERR);
+					});
+				if let Some(ext) = &transform.output_extension {
+					page.path.set_extension(ext);
+				}
+			} else if let Some(command) = external_command(&page, builder) {
+				debug!("generating {:?} with {:?}", &page.path, &command.command);
+				page.content = runner::run_pipe(command, &page.content).unwrap_or_else(|err| {
+					error!("Unable to generate {:?}! {}", &page.path, err);
+					process::exit(exitcode::DATAERR);
+				});
+				if let Some(ext) = &command.output_extension {
+					page.path.set_extension(ext);
+				}
+			}
+		}
+	}
+	if !page.shortcodes.is_empty() {
+		page.content = shortcodes::restore(&page.content, &page.shortcodes);
+	}
+	match page.path.as_path().extension().unwrap_or_default().to_str() {
+		Some("html") if renderers.plugins => {
+			for (plugin, function) in plugins.html_transforms() {
+				trace!(
+					"transforming {:?} with plugin {:?}",
+					&page.path,
+					&plugin.name
+				);
+				page.content = plugin
+					.call_str(function, &page.content)
+					.unwrap_or_else(|err| {
+						error!("Unable to transform {:?}! {}", &page.path, err);
+						process::exit(exitcode::DATAERR);
+					});
+			}
+		}
 		_ => (),
 	}
 	match page.path.as_path().extension().unwrap_or_default().to_str() {
@@ -287,12 +646,11 @@ fn render_page(mut page: Page, renderers: &Renderers) -> Page {
 fn complete_site_page(
 	mut page: Page,
 	site: Site,
-	renderers: &Renderers,
-	input_dir: &Path,
-	dirs: &Dirs,
+	builder: &Builder,
 	partials: InMemorySource,
+	plugins: &Plugins,
 ) -> Page {
-	if !renderers.layout {
+	if !has_layout(&page, builder) {
 		return page;
 	}
 
@@ -305,11 +663,14 @@ fn complete_site_page(
 
 		debug!("laying out {:?}", &page.path);
 
-		let template_path = input_dir.join(&dirs.layout_dir).join(layout);
+		let template_path = builder
+			.input_dir
+			.join(&builder.default_dirs.layout_dir)
+			.join(layout);
 
 		match fs::read_to_string(&template_path) {
 			Ok(template_content) => {
-				page.content = render_liquid(&template_content, &page, &site, partials)
+				page.content = render_liquid(&template_content, &page, &site, partials, plugins)
 					.unwrap_or_else(|err| {
 						error!("Unable to build layout for {:?}! {}", &page.path, err);
 						process::exit(exitcode::DATAERR);
@@ -334,6 +695,245 @@ fn complete_site_page(
 	page
 }
 
+fn minify_page(page: &Page, renderers: &Renderers) -> Option<String> {
+	if !renderers.minify {
+		return None;
+	}
+
+	trace!("minifying {:?}", &page.path);
+	render_minified(&page.path, &page.content).unwrap_or_else(|err| {
+		warn!("Unable to minify {:?}! {}", &page.path, err);
+		None
+	})
+}
+
+fn external_command<'a>(page: &Page, builder: &'a Builder) -> Option<&'a ExternalCommand> {
+	if !builder.renderers.commands {
+		return None;
+	}
+
+	let ext = page.source.as_path().extension()?.to_str()?;
+	builder
+		.commands
+		.iter()
+		.find(|command| command.extension == ext)
+}
+
+fn plugin_transform<'a>(
+	page: &Page,
+	builder: &Builder,
+	plugins: &'a Plugins,
+) -> Option<(&'a Plugin, &'a Transform)> {
+	if !builder.renderers.plugins {
+		return None;
+	}
+
+	plugins.transform(page.source.as_path().extension()?.to_str()?)
+}
+
+fn has_layout(page: &Page, builder: &Builder) -> bool {
+	match page.data.get("layout") {
+		Some(Value::Scalar(layout)) => {
+			builder.renderers.layout
+				&& !layout.to_owned().into_string().is_empty()
+				&& external_command(page, builder).is_none_or(|command| command.layout)
+		}
+		_ => false,
+	}
+}
+
+fn page_title(page: &Page) -> Option<String> {
+	match page.data.get("title") {
+		Some(Value::Scalar(title)) => Some(title.to_owned().into_string().to_string()),
+		_ => None,
+	}
+}
+
+fn output_path(page: &Page, builder: &Builder, plugins: &Plugins) -> PathBuf {
+	let renderers = &builder.renderers;
+	let mut path = page.path.to_owned();
+	match path.as_path().extension().unwrap_or_default().to_str() {
+		Some("md") if renderers.markdown => path.set_extension("html"),
+		Some("scss") if renderers.sass => path.set_extension("css"),
+		_ => match plugin_transform(page, builder, plugins) {
+			Some((_, transform)) => match &transform.output_extension {
+				Some(ext) => path.set_extension(ext),
+				None => false,
+			},
+			None => match external_command(page, builder).and_then(|c| c.output_extension.as_ref())
+			{
+				Some(ext) => path.set_extension(ext),
+				None => false,
+			},
+		},
+	};
+
+	if let (true, Some(Value::Scalar(layout))) =
+		(has_layout(page, builder), page.data.get("layout"))
+	{
+		if let Some(ext) = Path::new(layout.to_owned().into_string().as_str()).extension() {
+			path.set_extension(ext);
+		}
+	}
+
+	path
+}
+
+type RenderedAsset = (Option<(String, String)>, Vec<(String, String)>);
+
+fn render_asset(builder: &Builder, path: &Path, content: &[u8]) -> RenderedAsset {
+	let name = path.to_string_lossy().to_string();
+
+	let fingerprinted = if builder.renderers.fingerprint {
+		let fingerprinted = builder.assets.fingerprint(path, content);
+		let output_file = builder.output.as_path().join(&fingerprinted);
+
+		trace!("writing {:?}", &fingerprinted);
+		fs::write(&output_file, content).unwrap_or_else(|err| {
+			error!("Unable to write to {:?}! {}", &output_file, err);
+			process::exit(exitcode::IOERR);
+		});
+
+		Some(fingerprinted.to_string_lossy().to_string())
+	} else {
+		None
+	};
+
+	let mut integrity = Vec::new();
+	if builder.renderers.integrity {
+		trace!("hashing {:?}", path);
+
+		let hash = assets::integrity(content);
+		if let Some(fingerprinted) = &fingerprinted {
+			integrity.push((fingerprinted.to_owned(), hash.to_owned()));
+		}
+		integrity.push((name.to_owned(), hash));
+	}
+
+	(fingerprinted.map(|f| (name, f)), integrity)
+}
+
+fn render_search_entry(page: &Page, search: &Search) -> Option<SearchEntry> {
+	if page.path.as_path().extension().unwrap_or_default() != "html" {
+		return None;
+	}
+	if let Some(Value::Scalar(enabled)) = page.data.get("search") {
+		if enabled.to_bool() == Some(false) {
+			trace!("excluding {:?} from search index", &page.path);
+			return None;
+		}
+	}
+
+	trace!("indexing {:?}", &page.path);
+
+	let mut title = match page.data.get("title") {
+		Some(Value::Scalar(title)) => title.to_owned().into_string().to_string(),
+		_ => String::new(),
+	};
+	let mut headings = Vec::new();
+	let mut text = String::new();
+
+	let mut ignored = 0;
+	let mut heading: Option<String> = None;
+	let mut in_title = false;
+	for token in html::tokenize(&page.content) {
+		match token {
+			Token::Start { name, .. } | Token::End { name } if name == "title" => {
+				in_title = !in_title;
+			}
+			Token::Start { name, .. } => match name.as_ref() {
+				_ if html::IGNORED_ELEMENTS.contains(&name.as_str()) => ignored += 1,
+				"h1" | "h2" | "h3" | "h4" | "h5" | "h6" if ignored == 0 => {
+					heading = Some(String::new())
+				}
+				_ if BLOCK_ELEMENTS.contains(&name.as_str()) => text.push(' '),
+				_ => (),
+			},
+			Token::End { name } => match name.as_ref() {
+				_ if html::IGNORED_ELEMENTS.contains(&name.as_str()) => {
+					ignored = usize::saturating_sub(ignored, 1)
+				}
+				"h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+					if let Some(heading) = heading.take() {
+						let heading = collapse_whitespace(&heading);
+						if !heading.is_empty() {
+							headings.push(heading);
+						}
+					}
+					text.push(' ');
+				}
+				_ if BLOCK_ELEMENTS.contains(&name.as_str()) => text.push(' '),
+				_ => (),
+			},
+			Token::Text(content) if in_title => {
+				if title.is_empty() {
+					title = collapse_whitespace(&content);
+				}
+			}
+			Token::Text(content) if ignored == 0 => {
+				if let Some(heading) = heading.as_mut() {
+					heading.push_str(&content);
+				}
+				text.push_str(&content);
+			}
+			Token::Text(_) => (),
+		}
+	}
+
+	if title.is_empty() {
+		title = headings
+			.first()
+			.cloned()
+			.unwrap_or_else(|| page.path.to_string_lossy().to_string());
+	}
+
+	let mut text = collapse_whitespace(&text);
+	if search.text_length > 0 {
+		if let Some((index, _)) = text.char_indices().nth(search.text_length) {
+			text.truncate(index);
+		}
+	}
+
+	Some(SearchEntry {
+		url: page.path.to_owned(),
+		title,
+		headings,
+		text,
+	})
+}
+
+const BLOCK_ELEMENTS: &[&str] = &[
+	"address",
+	"article",
+	"aside",
+	"blockquote",
+	"br",
+	"dd",
+	"div",
+	"dl",
+	"dt",
+	"figcaption",
+	"figure",
+	"footer",
+	"header",
+	"hr",
+	"li",
+	"main",
+	"ol",
+	"p",
+	"pre",
+	"section",
+	"table",
+	"td",
+	"th",
+	"tr",
+	"ul",
+];
+
+fn collapse_whitespace(input: &str) -> String {
+	input.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
 pub fn run_builder(builder: &Builder) -> Result<(), Box<dyn Error>> {
 	info!("Generating pages in {:?}", &builder.input_dir);
 
@@ -343,6 +943,21 @@ pub fn run_builder(builder: &Builder) -> Result<(), Box<dyn Error>> {
 
 	fs::create_dir_all(&builder.output)?;
 
+	let plugins = if builder.renderers.plugins {
+		let builtins = liquid_builtins();
+		let builtin_filters = builtins
+			.filters()
+			.map(|filter| filter.name())
+			.collect::<Vec<_>>();
+
+		Plugins::load(&builder.plugins, &builtin_filters).unwrap_or_else(|err| {
+			error!("Unable to load plugin {}", err);
+			process::exit(exitcode::CONFIG);
+		})
+	} else {
+		Plugins::default()
+	};
+
 	let mut partials = InMemorySource::new();
 	if builder.renderers.liquid {
 		for file in read_path(
@@ -368,6 +983,31 @@ pub fn run_builder(builder: &Builder) -> Result<(), Box<dyn Error>> {
 		}
 	}
 
+	let mut templates = shortcodes::Templates::new();
+	if builder.renderers.shortcodes {
+		for file in read_path(
+			&builder
+				.input_dir
+				.as_path()
+				.join(&builder.default_dirs.shortcode_dir),
+		) {
+			trace!(
+				"loading {:?}",
+				file.as_path().file_name().unwrap_or_default()
+			);
+			templates.insert(
+				file.file_stem()
+					.unwrap_or_default()
+					.to_string_lossy()
+					.to_string(),
+				fs::read_to_string(&file).unwrap_or_else(|err| {
+					warn!("Unable to read {:?}! {}", &file, err);
+					String::new()
+				}),
+			);
+		}
+	}
+
 	let data = if builder.renderers.data {
 		read_path(
 			&builder
@@ -378,12 +1018,29 @@ pub fn run_builder(builder: &Builder) -> Result<(), Box<dyn Error>> {
 		.iter()
 		.par_bridge()
 		.filter_map(|path| read_data(path.to_owned()))
+		.chain(plugins.load_data())
 		.collect::<Vec<_>>()
 	} else {
 		vec![]
 	};
 
-	let input = read_path(&builder.input_dir);
+	let mut input = read_path(&builder.input_dir);
+
+	let mut defaults = builder.default_vars.to_owned();
+	if let Some(index) = input
+		.iter()
+		.position(|path| path.file_name().unwrap_or_default() == DEFAULTS_FILE)
+	{
+		if let Some(vars) = read_data(input.remove(index)) {
+			defaults.extend(vars);
+		}
+	}
+
+	let scoped_vars = builder
+		.scoped_vars
+		.iter()
+		.map(|scoped| Ok((glob::Pattern::new(&scoped.path)?, scoped.vars.to_owned())))
+		.collect::<Result<Vec<_>, glob::PatternError>>()?;
 
 	let files = input
 		.iter()
@@ -394,6 +1051,20 @@ pub fn run_builder(builder: &Builder) -> Result<(), Box<dyn Error>> {
 			let input_file = builder.input_dir.as_path().join(p);
 			let output_file = builder.output.as_path().join(p);
 
+			if builder.renderers.minify {
+				if let Some(minified) = fs::read_to_string(&input_file)
+					.ok()
+					.and_then(|content| render_minified(p, &content).ok().flatten())
+				{
+					trace!("minifying {:?}", p);
+					fs::write(&output_file, minified).unwrap_or_else(|err| {
+						error!("Unable to write to {:?}! {}", &output_file, err);
+						process::exit(exitcode::IOERR);
+					});
+					return;
+				}
+			}
+
 			trace!(
 				"copying {:?}",
 				input_file.as_path().file_name().unwrap_or_default()
@@ -405,13 +1076,156 @@ pub fn run_builder(builder: &Builder) -> Result<(), Box<dyn Error>> {
 		})
 		.collect::<Vec<_>>();
 
-	let pages = input
+	let mut pages = input
 		.iter()
 		.par_bridge()
-		.filter_map(|path| create_page(path.to_owned(), &builder.default_vars, &builder.renderers))
+		.filter_map(|path| {
+			let mut defaults = defaults.to_owned();
+			let relative = path.strip_prefix(&builder.input_dir).unwrap_or(path);
+			for (pattern, vars) in &scoped_vars {
+				if pattern.matches_path(relative) {
+					defaults.extend(vars.to_owned());
+				}
+			}
+
+			create_page(path.to_owned(), &defaults, &builder.renderers)
+		})
 		.collect::<Vec<_>>();
 
-	let mut site = Site { pages, files, data };
+	let mut translations = Object::new();
+	if builder.i18n.is_enabled() {
+		for file in read_path(
+			&builder
+				.input_dir
+				.as_path()
+				.join(&builder.default_dirs.i18n_dir),
+		) {
+			let lang = file
+				.file_stem()
+				.unwrap_or_default()
+				.to_string_lossy()
+				.to_string();
+			if let Some(strings) = read_data(file) {
+				translations.insert(lang.into(), Value::Object(strings));
+			}
+		}
+
+		for page in pages.iter_mut() {
+			let lang = match builder.i18n.split_language(&page.source) {
+				(Some(lang), _) => lang,
+				(None, _) if page.data.contains_key("lang") => continue,
+				(None, _) => match builder.i18n.default_language() {
+					Some(lang) => lang.to_owned(),
+					None => continue,
+				},
+			};
+			page.data.insert("lang".into(), Value::scalar(lang));
+		}
+
+		let page_translations = pages
+			.par_iter()
+			.map(|page| {
+				let (_, key) = builder.i18n.split_language(&page.source);
+				let mut translations = pages
+					.iter()
+					.filter(|other| {
+						other.source != page.source
+							&& builder.i18n.split_language(&other.source).1 == key
+					})
+					.filter_map(|other| {
+						let path = output_path(other, builder, &plugins);
+						Some(Translation {
+							lang: match other.data.get("lang") {
+								Some(Value::Scalar(lang)) => {
+									lang.to_owned().into_string().to_string()
+								}
+								_ => return None,
+							},
+							title: page_title(other)
+								.unwrap_or_else(|| path.to_string_lossy().to_string()),
+							path,
+						})
+					})
+					.collect::<Vec<_>>();
+				translations.sort_by(|a, b| a.lang.cmp(&b.lang));
+				translations
+			})
+			.collect::<Vec<_>>();
+
+		for (page, translations) in pages.iter_mut().zip(page_translations) {
+			page.translations = translations;
+		}
+	}
+
+	let mut site = Site {
+		pages,
+		files,
+		data,
+		assets: assets::Manifest::new(),
+		integrity: assets::Manifest::new(),
+		images: images::Manifest::new(),
+		languages: builder.i18n.languages.to_owned(),
+		i18n: translations,
+	};
+
+	if builder.renderers.images {
+		site.images = site
+			.files
+			.par_iter()
+			.filter(|p| {
+				builder.images.matches(p) && !site.pages.iter().any(|page| &page.path == *p)
+			})
+			.filter_map(|p| {
+				debug!("resizing {:?}", p);
+				match builder.images.render(&builder.output, p) {
+					Ok(image) => Some((p.to_string_lossy().to_string(), image)),
+					Err(err) => {
+						warn!("Unable to resize {:?}! {}", p, err);
+						None
+					}
+				}
+			})
+			.collect();
+	}
+
+	if builder.renderers.fingerprint || builder.renderers.integrity {
+		let assets = site
+			.files
+			.par_iter()
+			.filter(|p| {
+				builder.assets.matches(p) && !site.pages.iter().any(|page| &page.path == *p)
+			})
+			.filter_map(|p| {
+				let output_file = builder.output.as_path().join(p);
+				match fs::read(&output_file) {
+					Ok(content) => Some(render_asset(builder, p, &content)),
+					Err(err) => {
+						warn!("Unable to read {:?}! {}", &output_file, err);
+						None
+					}
+				}
+			})
+			.collect::<Vec<_>>();
+		site.add_assets(assets);
+	}
+
+	let mut targets = WikiTargets::default();
+	if builder.renderers.markdown && builder.markdown.wiki_links {
+		let mut pages = site.pages.iter().collect::<Vec<_>>();
+		pages.sort_by(|a, b| a.source.cmp(&b.source));
+
+		for page in &pages {
+			if let Some(title) = page_title(page) {
+				targets.insert(&title, &output_path(page, builder, &plugins));
+			}
+		}
+		for page in &pages {
+			let url = output_path(page, builder, &plugins);
+			targets.insert(&page.source.to_string_lossy(), &url);
+			targets.insert(&page.source.with_extension("").to_string_lossy(), &url);
+			targets.insert(&url.to_string_lossy(), &url);
+		}
+	}
 
 	site.pages = site
 		.pages
@@ -422,7 +1236,10 @@ pub fn run_builder(builder: &Builder) -> Result<(), Box<dyn Error>> {
 				page.to_owned(),
 				site.to_owned(),
 				&builder.renderers,
+				external_command(page, builder),
 				partials.to_owned(),
+				&templates,
+				&plugins,
 			)
 		})
 		.inspect(|page| {
@@ -434,32 +1251,172 @@ pub fn run_builder(builder: &Builder) -> Result<(), Box<dyn Error>> {
 				process::exit(exitcode::IOERR);
 			});
 		})
-		.map(|page| render_page(page, &builder.renderers))
+		.map(|page| render_page(page, builder, &plugins, &targets))
 		.collect::<Vec<_>>();
 
-	site.pages
+	if builder.renderers.markdown && builder.markdown.wiki_links {
+		let backlinks = site
+			.pages
+			.par_iter()
+			.map(|page| {
+				let url = output_path(page, builder, &plugins);
+				let mut backlinks = site
+					.pages
+					.iter()
+					.filter(|other| other.source != page.source && other.links.contains(&url))
+					.map(|other| PageRef::new(other, builder, &plugins))
+					.collect::<Vec<_>>();
+				backlinks.sort_by(|a, b| a.path.cmp(&b.path));
+				backlinks
+			})
+			.collect::<Vec<_>>();
+
+		for (page, backlinks) in site.pages.iter_mut().zip(backlinks) {
+			page.backlinks = backlinks;
+		}
+	}
+
+	if builder.renderers.related {
+		debug!("finding related pages");
+
+		let mut indices = (0..site.pages.len())
+			.filter(|index| {
+				output_path(&site.pages[*index], builder, &plugins)
+					.extension()
+					.unwrap_or_default()
+					== "html"
+			})
+			.collect::<Vec<_>>();
+		indices.sort_by(|a, b| site.pages[*a].path.cmp(&site.pages[*b].path));
+
+		let documents = indices
+			.par_iter()
+			.map(|index| {
+				let page = &site.pages[*index];
+				builder.related.document(&page.data, &page.content)
+			})
+			.collect::<Vec<_>>();
+		let related = builder
+			.related
+			.rank(&documents)
+			.iter()
+			.map(|ranked| {
+				ranked
+					.iter()
+					.map(|other| PageRef::new(&site.pages[indices[*other]], builder, &plugins))
+					.collect::<Vec<_>>()
+			})
+			.collect::<Vec<_>>();
+
+		for (index, related) in indices.iter().zip(related) {
+			site.pages[*index].related = related;
+		}
+	}
+
+	if builder.renderers.fingerprint || builder.renderers.integrity {
+		let assets = site
+			.pages
+			.par_iter()
+			.filter(|page| builder.assets.matches(&page.path) && !has_layout(page, builder))
+			.map(|page| {
+				let content = minify_page(page, &builder.renderers);
+				render_asset(
+					builder,
+					&page.path,
+					content.as_ref().unwrap_or(&page.content).as_bytes(),
+				)
+			})
+			.collect::<Vec<_>>();
+		site.add_assets(assets);
+	}
+
+	let pages = site
+		.pages
 		.iter()
 		.par_bridge()
 		.map(|page| {
 			complete_site_page(
 				page.to_owned(),
 				site.to_owned(),
-				&builder.renderers,
-				&builder.input_dir,
-				&builder.default_dirs,
+				builder,
 				partials.to_owned(),
+				&plugins,
 			)
 		})
-		.for_each(|page| {
+		.collect::<Vec<_>>();
+
+	let hashes = pages
+		.par_iter()
+		.map(|page| {
 			let output_file = builder.output.as_path().join(&page.path);
+			let mut content =
+				minify_page(page, &builder.renderers).unwrap_or_else(|| page.content.to_owned());
+
+			let mut hashes = InlineHashes::default();
+			if builder.renderers.integrity
+				&& page.path.as_path().extension().unwrap_or_default() == "html"
+			{
+				trace!("hashing {:?}", &page.path);
+				hashes = assets::inline_hashes(&content);
+
+				if builder.csp.meta {
+					content = builder
+						.csp
+						.insert_meta(&content, &builder.csp.render_policy(&hashes));
+				}
+			}
 
 			trace!("writing {:?}", &page.path);
-			fs::write(&output_file, &page.content).unwrap_or_else(|err| {
+			fs::write(&output_file, &content).unwrap_or_else(|err| {
 				error!("Unable to write to {:?}! {}", &output_file, err);
 				process::exit(exitcode::IOERR);
 			});
+
+			hashes
+		})
+		.reduce(InlineHashes::default, |mut a, b| {
+			a.extend(b);
+			a
 		});
 
+	if let (true, Some(output)) = (builder.renderers.integrity, &builder.csp.output) {
+		debug!("generating {:?}", output);
+
+		let mut headers = Object::new();
+		headers.insert(
+			"content-security-policy".into(),
+			Value::scalar(builder.csp.render_policy(&hashes)),
+		);
+		fs::write(
+			builder.output.as_path().join(output),
+			toml::to_string(&headers)?,
+		)?;
+	}
+
+	if builder.renderers.search {
+		debug!("generating {:?}", &builder.search.output);
+
+		let mut index = pages
+			.par_iter()
+			.filter_map(|page| render_search_entry(page, &builder.search))
+			.collect::<Vec<_>>();
+		index.sort_by(|a, b| a.url.cmp(&b.url));
+
+		fs::write(
+			builder.output.as_path().join(&builder.search.output),
+			serde_json::to_string(&index)?,
+		)?;
+	}
+
+	if builder.renderers.fingerprint {
+		debug!("generating {:?}", &builder.assets.manifest);
+
+		fs::write(
+			builder.output.as_path().join(&builder.assets.manifest),
+			serde_json::to_string(&site.assets)?,
+		)?;
+	}
+
 	if let Ok(dir) = fs::read_dir(&builder.input_dir) {
 		dir.filter_map(Result::ok)
 			.filter(|e| e.file_type().map(|t| t.is_symlink()).unwrap_or(false))
@@ -478,5 +1435,31 @@ pub fn run_builder(builder: &Builder) -> Result<(), Box<dyn Error>> {
 			});
 	}
 
+	if builder.renderers.link_checker {
+		debug!("checking links in {:?}", &builder.output);
+
+		let mut html_files = pages
+			.iter()
+			.map(|page| (page.path.to_owned(), page.source.to_owned()))
+			.collect::<Vec<_>>();
+		html_files.extend(
+			site.files
+				.iter()
+				.filter(|p| !pages.iter().any(|page| page.source == **p))
+				.map(|p| (p.to_owned(), p.to_owned())),
+		);
+		html_files.retain(|(path, _)| path.as_path().extension().unwrap_or_default() == "html");
+
+		let broken = linkchecker::run_link_checker(
+			&builder.input_dir,
+			&builder.output,
+			&html_files,
+			&builder.link_checker,
+		);
+		if broken > 0 && builder.link_checker.fail {
+			return Err(format!("found {} broken links", broken).into());
+		}
+	}
+
 	Ok(())
 }
@@ -0,0 +1,213 @@
+#![warn(clippy::all)]
+
+use crate::http::Vhost;
+use regex::Regex;
+use std::error::Error;
+
+#[derive(Clone, Debug)]
+pub enum HostPattern {
+	Exact(String),
+	Wildcard(String),
+	Regex(Regex),
+}
+
+impl HostPattern {
+	pub fn parse(pattern: &str) -> Result<Self, Box<dyn Error>> {
+		if let Some(regex) = pattern.strip_prefix('~') {
+			let regex = Regex::new(&["(?i)", regex].concat())
+				.map_err(|err| format!("{:?}: {}", pattern, err))?;
+			return Ok(HostPattern::Regex(regex));
+		}
+
+		let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+		match pattern.strip_prefix("*.") {
+			Some(suffix) if !suffix.is_empty() && !suffix.contains('*') => {
+				Ok(HostPattern::Wildcard(suffix.to_owned()))
+			}
+			_ if pattern.contains('*') || pattern.is_empty() => {
+				Err(format!("{:?}: invalid host pattern", pattern).into())
+			}
+			_ => Ok(HostPattern::Exact(pattern)),
+		}
+	}
+
+	// Wildcards only match a single label, the same as wildcard certificates.
+	fn matches(&self, host: &str) -> bool {
+		match self {
+			HostPattern::Exact(name) => name == host,
+			HostPattern::Wildcard(suffix) => match host.strip_suffix(suffix.as_str()) {
+				Some(label) => match label.strip_suffix('.') {
+					Some(label) => !label.is_empty() && !label.contains('.'),
+					None => false,
+				},
+				None => false,
+			},
+			HostPattern::Regex(regex) => regex.is_match(host),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HostMatcher {
+	patterns: Vec<(HostPattern, usize)>,
+	default: Option<usize>,
+}
+
+impl HostMatcher {
+	pub fn new<'a>(vhosts: impl IntoIterator<Item = &'a Vhost>) -> Result<Self, Box<dyn Error>> {
+		let mut matcher = HostMatcher::default();
+		let mut default_host: Option<&str> = None;
+
+		for (index, vhost) in vhosts.into_iter().enumerate() {
+			for name in vhost.names() {
+				matcher.patterns.push((HostPattern::parse(name)?, index));
+			}
+
+			if vhost.default {
+				if let Some(default_host) = default_host {
+					return Err(format!(
+						"{:?} and {:?} can't both be the default vhost",
+						default_host, vhost.host
+					)
+					.into());
+				}
+				default_host = Some(&vhost.host);
+				matcher.default = Some(index);
+			}
+		}
+
+		Ok(matcher)
+	}
+
+	// Exact names are checked first, then the longest matching wildcard, then regexes in order.
+	pub fn find(&self, host: Option<&str>) -> Option<usize> {
+		let host = match host {
+			Some(host) => host.trim_end_matches('.').to_ascii_lowercase(),
+			None => return self.default,
+		};
+
+		let exact = self.patterns.iter().find(|(pattern, _)| {
+			matches!(pattern, HostPattern::Exact(_)) && pattern.matches(&host)
+		});
+		let wildcard = || {
+			self.patterns
+				.iter()
+				.filter(|(pattern, _)| pattern.matches(&host))
+				.filter_map(|(pattern, index)| match pattern {
+					HostPattern::Wildcard(suffix) => Some((suffix.len(), *index)),
+					_ => None,
+				})
+				.rev()
+				.max_by_key(|(len, _)| *len)
+		};
+		let regex = || {
+			self.patterns.iter().find(|(pattern, _)| {
+				matches!(pattern, HostPattern::Regex(_)) && pattern.matches(&host)
+			})
+		};
+
+		exact
+			.map(|(_, index)| *index)
+			.or_else(|| wildcard().map(|(_, index)| index))
+			.or_else(|| regex().map(|(_, index)| *index))
+			.or(self.default)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_derive::Deserialize;
+
+	#[derive(Deserialize)]
+	struct Config {
+		vhost: Vec<Vhost>,
+	}
+
+	fn matcher(config: &str) -> Result<HostMatcher, Box<dyn Error>> {
+		let config: Config = toml::from_str(config).unwrap();
+		HostMatcher::new(&config.vhost)
+	}
+
+	fn matches(pattern: &str, host: &str) -> bool {
+		HostPattern::parse(pattern).unwrap().matches(host)
+	}
+
+	#[test]
+	fn wildcards_match_a_single_label() {
+		assert!(matches("*.example.com", "www.example.com"));
+		assert!(!matches("*.example.com", "example.com"));
+		assert!(!matches("*.example.com", "a.b.example.com"));
+		assert!(!matches("*.example.com", "wwwexample.com"));
+		assert!(!matches("*.example.com", ".example.com"));
+	}
+
+	#[test]
+	fn exact_names_are_normalised() {
+		assert!(matches("Example.COM.", "example.com"));
+		assert!(!matches("example.com", "www.example.com"));
+		assert!(matches("~^(www\\.)?EXAMPLE\\.com$", "www.example.com"));
+	}
+
+	#[test]
+	fn invalid_patterns_are_rejected() {
+		for pattern in &["", ".", "*", "*.", "a.*.com", "*.*.com", "~("] {
+			assert!(HostPattern::parse(pattern).is_err(), "{:?}", pattern);
+		}
+	}
+
+	#[test]
+	fn hosts_are_found_in_order_of_precedence() {
+		let matcher = matcher(
+			r#"
+			[[vhost]]
+			host = "~^www\\."
+
+			[[vhost]]
+			host = "*.example.com"
+
+			[[vhost]]
+			host = "*.b.example.com"
+
+			[[vhost]]
+			host = "www.example.com"
+			aliases = ["example.com"]
+
+			[[vhost]]
+			host = "default.example.net"
+			default = true
+			"#,
+		)
+		.unwrap();
+
+		assert_eq!(matcher.find(Some("www.example.com")), Some(3));
+		assert_eq!(matcher.find(Some("EXAMPLE.com.")), Some(3));
+		assert_eq!(matcher.find(Some("a.example.com")), Some(1));
+		assert_eq!(matcher.find(Some("a.b.example.com")), Some(2));
+		assert_eq!(matcher.find(Some("www.b.example.com")), Some(2));
+		assert_eq!(matcher.find(Some("www.example.org")), Some(0));
+		assert_eq!(matcher.find(Some("example.org")), Some(4));
+		assert_eq!(matcher.find(None), Some(4));
+	}
+
+	#[test]
+	fn hosts_without_a_default_are_not_found() {
+		let matcher = matcher("[[vhost]]\nhost = \"example.com\"").unwrap();
+		assert_eq!(matcher.find(Some("example.org")), None);
+		assert_eq!(matcher.find(None), None);
+	}
+
+	#[test]
+	fn only_one_vhost_can_be_the_default() {
+		let vhosts = r#"
+			[[vhost]]
+			host = "example.com"
+			default = true
+
+			[[vhost]]
+			host = "example.org"
+			default = true
+			"#;
+		assert!(matcher(vhosts).is_err());
+	}
+}
@@ -0,0 +1,94 @@
+#![warn(clippy::all)]
+
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::{
+	states::RawKind, BufferQueue, TagKind, Token as HtmlToken, TokenSink, TokenSinkResult,
+	Tokenizer, TokenizerOpts,
+};
+
+// Elements whose text isn't part of a page's content.
+pub const IGNORED_ELEMENTS: &[&str] = &["head", "nav", "script", "style", "template", "noscript"];
+
+#[derive(Clone, Debug)]
+pub enum Token {
+	Start {
+		name: String,
+		attrs: Vec<(String, String)>,
+		line: u64,
+	},
+	End {
+		name: String,
+	},
+	Text(String),
+}
+
+impl Token {
+	pub fn attr(&self, key: &str) -> Option<&str> {
+		match self {
+			Token::Start { attrs, .. } => attrs
+				.iter()
+				.find(|(name, _)| name == key)
+				.map(|(_, value)| value.as_str()),
+			_ => None,
+		}
+	}
+}
+
+struct Sink {
+	tokens: Vec<Token>,
+}
+
+impl TokenSink for Sink {
+	type Handle = ();
+
+	fn process_token(&mut self, token: HtmlToken, line: u64) -> TokenSinkResult<()> {
+		match token {
+			HtmlToken::TagToken(tag) => {
+				let name = tag.name.to_string();
+				match tag.kind {
+					TagKind::StartTag => {
+						let raw = match name.as_ref() {
+							"script" => Some(RawKind::ScriptData),
+							"style" | "xmp" | "iframe" | "noembed" | "noframes" => {
+								Some(RawKind::Rawtext)
+							}
+							"title" | "textarea" => Some(RawKind::Rcdata),
+							_ => None,
+						};
+						self.tokens.push(Token::Start {
+							name,
+							attrs: tag
+								.attrs
+								.iter()
+								.map(|attr| (attr.name.local.to_string(), attr.value.to_string()))
+								.collect(),
+							line,
+						});
+						if let (Some(kind), false) = (raw, tag.self_closing) {
+							return TokenSinkResult::RawData(kind);
+						}
+					}
+					TagKind::EndTag => self.tokens.push(Token::End { name }),
+				}
+			}
+			HtmlToken::CharacterTokens(text) => match self.tokens.last_mut() {
+				Some(Token::Text(last)) => last.push_str(&text),
+				_ => self.tokens.push(Token::Text(text.to_string())),
+			},
+			_ => (),
+		}
+
+		TokenSinkResult::Continue
+	}
+}
+
+pub fn tokenize(input: &str) -> Vec<Token> {
+	let mut queue = BufferQueue::new();
+	queue.push_back(StrTendril::from_slice(input));
+
+	let mut tokenizer = Tokenizer::new(Sink { tokens: vec![] }, TokenizerOpts::default());
+	let _ = tokenizer.feed(&mut queue);
+	tokenizer.end();
+
+	tokenizer.sink.tokens
+}
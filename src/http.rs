@@ -1,21 +1,42 @@
 #![warn(clippy::all)]
 
+use crate::{
+	acme::{self, Challenges, ACME_TLS_ALPN},
+	hosts::HostMatcher,
+	tls::{self, CertResolver, ClientCert, Tls},
+};
+use actix_files::NamedFile;
+use actix_http::{Extensions, HttpService, KeepAlive, Protocol};
+use actix_rt::net::TcpStream;
+use actix_service::{fn_service, map_config, pipeline_factory, ServiceFactoryExt};
+use actix_tls::accept::{
+	rustls::{Acceptor, TlsStream},
+	TlsError,
+};
 use actix_web::{
-	guard,
-	http::{header, StatusCode},
-	middleware::{Compress, DefaultHeaders, Logger, NormalizePath, TrailingSlash},
-	web, App, HttpRequest, HttpResponse, HttpServer, Scope,
+	dev::{self, AppConfig, Path, RequestHead, ResourceDef, ServiceRequest, ServiceResponse},
+	http::{
+		header::{self, HeaderName, HeaderValue},
+		Method, StatusCode, Uri,
+	},
+	middleware::{Compress, Logger, NormalizePath, TrailingSlash},
+	web::Bytes,
+	App, HttpMessage, HttpResponse, HttpServer,
 };
-use futures::future::Either;
 use log::{debug, info, trace};
-use rustls::{
-	sign, sign::CertifiedKey, Certificate, NoClientAuth, PrivateKey, ResolvesServerCertUsingSNI,
-	ServerConfig,
-};
+use percent_encoding::percent_decode_str;
+use rustls::Session;
 use serde_derive::Deserialize;
 use std::{
-	boxed::Box, collections::BTreeMap, default::Default, error::Error, fs::File, future,
-	future::Future, io::BufReader, iter, net::SocketAddr, path::PathBuf, sync::Arc,
+	boxed::Box,
+	collections::{BTreeMap, HashMap},
+	default::Default,
+	error::Error,
+	fs, io, iter,
+	net::SocketAddr,
+	path::PathBuf,
+	str::FromStr,
+	sync::{Arc, RwLock},
 };
 
 #[derive(Deserialize, Clone, Debug)]
@@ -23,15 +44,30 @@ use std::{
 pub struct Vhost {
 	pub host: String,
 
+	#[serde(default)]
+	pub aliases: Vec<String>,
+
+	#[serde(default)]
+	pub default: bool,
+
 	#[serde(default)]
 	pub files: Vec<Files>,
 
 	#[serde(default)]
 	pub redir: Vec<Redir>,
 
+	#[serde(default)]
+	pub error_pages: BTreeMap<String, PathBuf>,
+
 	pub tls: Option<Tls>,
 }
 
+impl Vhost {
+	pub fn names(&self) -> impl Iterator<Item = &String> {
+		iter::once(&self.host).chain(&self.aliases)
+	}
+}
+
 #[derive(Deserialize, Clone, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Files {
@@ -39,6 +75,19 @@ pub struct Files {
 	pub mount: String,
 
 	pub file_dir: PathBuf,
+
+	#[serde(default)]
+	pub resolve_html: bool,
+
+	#[serde(default)]
+	pub strip_html: bool,
+
+	#[serde(default = "default_trailing_slash")]
+	pub trailing_slash: String,
+}
+
+fn default_trailing_slash() -> String {
+	"ignore".to_string()
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -53,16 +102,9 @@ pub struct Redir {
 	pub permanent: bool,
 }
 
-#[derive(Deserialize, Clone, Debug)]
-#[serde(deny_unknown_fields)]
-pub struct Tls {
-	pub pemfiles: Vec<PathBuf>,
-	pub http_dest: Option<String>,
-}
-
 pub type Headers = BTreeMap<String, String>;
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Server {
 	#[serde(default)]
@@ -73,6 +115,24 @@ pub struct Server {
 
 	#[serde(default = "default_server_log_format")]
 	pub log_format: String,
+
+	#[serde(default)]
+	pub header_files: Vec<PathBuf>,
+
+	#[serde(default)]
+	pub watch_config: bool,
+
+	#[serde(default = "default_server_tls_reload_interval")]
+	pub tls_reload_interval: u64,
+
+	#[serde(default = "default_server_tls_expiry_warning")]
+	pub tls_expiry_warning: u64,
+
+	#[serde(default)]
+	pub tls: tls::ServerTls,
+
+	#[serde(default)]
+	pub acme: acme::Acme,
 }
 
 impl Default for Server {
@@ -81,6 +141,12 @@ impl Default for Server {
 			http_bind: vec![],
 			tls_bind: vec![],
 			log_format: default_server_log_format(),
+			header_files: vec![],
+			watch_config: false,
+			tls_reload_interval: default_server_tls_reload_interval(),
+			tls_expiry_warning: default_server_tls_expiry_warning(),
+			tls: tls::ServerTls::default(),
+			acme: acme::Acme::default(),
 		}
 	}
 }
@@ -89,59 +155,236 @@ fn default_server_log_format() -> String {
 	"%{Host}i %a \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %D".to_string()
 }
 
+fn default_server_tls_reload_interval() -> u64 {
+	3600
+}
+
+fn default_server_tls_expiry_warning() -> u64 {
+	14
+}
+
+pub fn load_header_files(server: &Server, headers: &Headers) -> Result<Headers, Box<dyn Error>> {
+	let mut loaded = Headers::new();
+	for file in &server.header_files {
+		debug!("loading headers from {:?}", file);
+		loaded.extend(toml::from_str::<Headers>(&fs::read_to_string(file)?)?);
+	}
+	loaded.extend(headers.to_owned());
+
+	Ok(loaded)
+}
+
 fn handle_not_found() -> HttpResponse {
 	HttpResponse::NotFound()
 		.content_type("text/html; charset=utf-8")
 		.body(include_str!("404.html"))
 }
 
-fn handle_redirect(
-	req: HttpRequest,
-	status: web::Data<StatusCode>,
-	dest: web::Data<String>,
-) -> HttpResponse {
+fn handle_forbidden() -> HttpResponse {
+	HttpResponse::Forbidden()
+		.content_type("text/html; charset=utf-8")
+		.body(include_str!("403.html"))
+}
+
+fn handle_redirect(path: &Path<String>, status: StatusCode, dest: &str) -> HttpResponse {
 	let mut dest = dest.to_string();
-	for (_, segment) in req.match_info().iter() {
+	for (_, segment) in path.iter() {
 		dest = [&dest, "/", segment].concat()
 	}
 
-	HttpResponse::build(*status.as_ref())
+	HttpResponse::build(status)
 		.append_header((header::LOCATION, dest))
 		.finish()
 }
 
-fn handle_https_redirect(req: HttpRequest, dest: web::Data<String>) -> HttpResponse {
+fn handle_https_redirect(path: &str, dest: &str) -> HttpResponse {
 	HttpResponse::PermanentRedirect()
-		.append_header((header::LOCATION, [dest.as_str(), req.path()].concat()))
+		.append_header((header::LOCATION, [dest, path].concat()))
 		.finish()
 }
 
-fn create_certified_key(pemfiles: &[PathBuf]) -> Result<CertifiedKey, Box<dyn Error>> {
-	let mut certs = Vec::new();
-	let mut keys = Vec::new();
-	for pemfile in pemfiles {
-		let mut reader = BufReader::new(File::open(pemfile)?);
-		for item in iter::from_fn(|| rustls_pemfile::read_one(&mut reader).transpose()) {
-			match item? {
-				rustls_pemfile::Item::X509Certificate(cert) => certs.push(Certificate(cert)),
-				rustls_pemfile::Item::PKCS8Key(key) => keys.push(PrivateKey(key)),
-				rustls_pemfile::Item::RSAKey(key) => keys.push(PrivateKey(key)),
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SlashPolicy {
+	Add,
+	Strip,
+	Ignore,
+}
+
+fn slash_policy(policy: &str) -> Result<SlashPolicy, Box<dyn Error>> {
+	match policy {
+		"add" => Ok(SlashPolicy::Add),
+		"strip" => Ok(SlashPolicy::Strip),
+		"ignore" => Ok(SlashPolicy::Ignore),
+		policy => Err(format!("unsupported trailing_slash policy {:?}", policy).into()),
+	}
+}
+
+fn request_host(req: &RequestHead) -> Option<String> {
+	req.headers
+		.get(header::HOST)
+		.and_then(|host| host.to_str().ok())
+		.or_else(|| req.uri.host())
+		.and_then(|host| Uri::from_str(host).ok())
+		.and_then(|uri| uri.host().map(str::to_owned))
+}
+
+type ErrorPages = HashMap<StatusCode, (String, Bytes)>;
+
+fn load_error_pages(vhost: &Vhost) -> Result<ErrorPages, Box<dyn Error>> {
+	let mut pages = ErrorPages::new();
+	for (code, file) in &vhost.error_pages {
+		let status = code
+			.parse()
+			.ok()
+			.and_then(|code| StatusCode::from_u16(code).ok())
+			.filter(|status| status.is_client_error() || status.is_server_error())
+			.ok_or_else(|| format!("{:?}: invalid error status code {:?}", vhost.host, code))?;
+
+		debug!("loading {} error page from {:?}", status, file);
+		let body = fs::read(file).map_err(|err| format!("{:?}: {}", file, err))?;
+		let mime = actix_files::file_extension_to_mime(
+			file.extension()
+				.and_then(|ext| ext.to_str())
+				.unwrap_or_default(),
+		);
+		let content_type = if mime.type_() == "text" {
+			format!("{}; charset=utf-8", mime)
+		} else {
+			mime.to_string()
+		};
+		pages.insert(status, (content_type, Bytes::from(body)));
+	}
+
+	Ok(pages)
+}
+
+pub struct Routes {
+	http: Router,
+	https: Router,
+	headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+#[derive(Clone)]
+pub struct RoutingTable(Arc<RwLock<Arc<Routes>>>);
+
+fn parse_headers(headers: &Headers) -> Result<Vec<(HeaderName, HeaderValue)>, Box<dyn Error>> {
+	headers
+		.iter()
+		.map(|(key, val)| {
+			Ok((
+				HeaderName::from_str(key).map_err(|err| format!("{:?}: {}", key, err))?,
+				HeaderValue::from_str(val).map_err(|err| format!("{:?}: {}", key, err))?,
+			))
+		})
+		.collect()
+}
+
+impl RoutingTable {
+	pub fn new(vhosts: &[Vhost], headers: &Headers) -> Result<Self, Box<dyn Error>> {
+		let routes = RoutingTable::prepare(vhosts, headers)?;
+
+		Ok(RoutingTable(Arc::new(RwLock::new(Arc::new(routes)))))
+	}
+
+	// Routes are built up front, so that a bad route is reported before anything is swapped.
+	pub fn prepare(vhosts: &[Vhost], headers: &Headers) -> Result<Routes, Box<dyn Error>> {
+		let error_pages = vhosts
+			.iter()
+			.map(|vhost| load_error_pages(vhost).map(Arc::new))
+			.collect::<Result<Vec<_>, _>>()?;
+
+		Ok(Routes {
+			http: configure_router(vhosts, &error_pages, false)?,
+			https: configure_router(vhosts, &error_pages, true)?,
+			headers: parse_headers(headers)?,
+		})
+	}
+
+	pub fn commit(&self, routes: Routes) {
+		trace!("swapping routing table");
+		match self.0.write() {
+			Ok(mut current) => *current = Arc::new(routes),
+			Err(err) => *err.into_inner() = Arc::new(routes),
+		}
+	}
+
+	fn load(&self) -> Arc<Routes> {
+		match self.0.read() {
+			Ok(routes) => routes.to_owned(),
+			Err(err) => err.into_inner().to_owned(),
+		}
+	}
+}
+
+struct ClientRules {
+	required: bool,
+	access: Vec<(String, Vec<glob::Pattern>)>,
+}
+
+impl ClientRules {
+	fn allows(&self, path: &str, cert: Option<&ClientCert>) -> bool {
+		// Refuse dot segments rather than guess how the file handler resolves them.
+		if path.split('/').any(|segment| segment == "..") {
+			return false;
+		}
+
+		let access = self
+			.access
+			.iter()
+			.find(|(prefix, _)| path == prefix || path.starts_with(&[prefix, "/"].concat()));
+		match (access, cert) {
+			(_, None) if self.required => false,
+			(None, _) => true,
+			(Some(_), None) => false,
+			(Some((_, subjects)), Some(cert)) => {
+				subjects.is_empty()
+					|| subjects
+						.iter()
+						.any(|subject| subject.matches(&cert.subject))
 			}
 		}
 	}
+}
 
-	let key = keys.get(0).ok_or("no valid keys found")?;
-	let signingkey = sign::any_supported_type(key).or(Err("unable to parse key"))?;
+struct VhostRouter {
+	host: String,
+	https_dest: Option<String>,
+	client_rules: Option<ClientRules>,
+	error_pages: Arc<ErrorPages>,
+	redir: Vec<(ResourceDef, StatusCode, String)>,
+	files: Vec<FilesRouter>,
+}
+
+struct FilesRouter {
+	mount: String,
+	dir: PathBuf,
+	resolve_html: bool,
+	strip_html: bool,
+	trailing_slash: SlashPolicy,
+}
 
-	Ok(CertifiedKey::new(certs, Arc::new(signingkey)))
+struct Router {
+	hosts: HostMatcher,
+	vhosts: Vec<VhostRouter>,
 }
 
-fn configure_vhost_scope(vhost: &Vhost, is_tls: bool) -> Option<Scope> {
+fn configure_vhost_router(
+	vhost: &Vhost,
+	error_pages: &Arc<ErrorPages>,
+	is_tls: bool,
+) -> Result<Option<VhostRouter>, Box<dyn Error>> {
 	if is_tls && vhost.tls.is_none() {
-		return None;
+		return Ok(None);
 	}
 
-	let mut scope = web::scope("/").guard(guard::Host(String::from(&vhost.host)));
+	let mut router = VhostRouter {
+		host: vhost.host.to_owned(),
+		https_dest: None,
+		client_rules: None,
+		error_pages: error_pages.to_owned(),
+		redir: vec![],
+		files: vec![],
+	};
 
 	if let Some(Tls {
 		http_dest: Some(dest),
@@ -149,121 +392,594 @@ fn configure_vhost_scope(vhost: &Vhost, is_tls: bool) -> Option<Scope> {
 	}) = &vhost.tls
 	{
 		if !is_tls {
-			return Some(
-				scope
-					.data(dest.to_owned())
-					.default_service(web::to(handle_https_redirect)),
-			);
+			router.https_dest = Some(dest.to_owned());
+			return Ok(Some(router));
 		}
 	}
 
-	for redir in vhost.redir.to_owned() {
+	if let Some(tls) = vhost.tls.as_ref().filter(|tls| !tls.client_ca.is_empty()) {
+		let mut access = Vec::new();
+		for rule in &tls.access {
+			let prefix = match rule.path.as_ref() {
+				"/" => "",
+				_ => rule.path.trim_end_matches('/'),
+			};
+			let subjects = rule
+				.subjects
+				.iter()
+				.map(|subject| {
+					glob::Pattern::new(subject).map_err(|err| format!("{:?}: {}", subject, err))
+				})
+				.collect::<Result<_, _>>()?;
+			access.push((prefix.to_owned(), subjects));
+		}
+		router.client_rules = Some(ClientRules {
+			required: tls.client_auth != "optional",
+			access,
+		});
+	}
+
+	for redir in vhost.redir.iter().cloned() {
 		let status = match redir.permanent {
 			true => StatusCode::PERMANENT_REDIRECT,
 			false => StatusCode::TEMPORARY_REDIRECT,
 		};
 		let target = match redir.target.as_ref() {
-			"/" => "",
+			"" => "/",
 			_ => &redir.target,
 		};
-		scope = scope.service(
-			web::resource(target)
-				.data(status)
-				.data(redir.dest)
-				.to(handle_redirect),
-		)
+		router
+			.redir
+			.push((ResourceDef::new(target), status, redir.dest));
 	}
 
-	for files in vhost.files.to_owned() {
+	for files in vhost.files.iter().cloned() {
 		let mount = match files.mount.as_ref() {
 			"/" => "",
-			_ => &files.mount,
+			_ => files.mount.trim_end_matches('/'),
 		};
-		scope = scope.service(
-			actix_files::Files::new(mount, &files.file_dir)
-				.index_file("index.html")
-				.prefer_utf8(true)
-				.disable_content_disposition(),
-		)
+		let dir = files
+			.file_dir
+			.canonicalize()
+			.map_err(|err| format!("{:?}: {}", files.file_dir, err))?;
+		let trailing_slash = slash_policy(&files.trailing_slash)
+			.map_err(|err| format!("{:?}: {}", files.file_dir, err))?;
+		router.files.push(FilesRouter {
+			mount: mount.to_owned(),
+			dir,
+			resolve_html: files.resolve_html || files.strip_html,
+			strip_html: files.strip_html,
+			trailing_slash,
+		});
+	}
+
+	Ok(Some(router))
+}
+
+fn configure_router(
+	vhosts: &[Vhost],
+	error_pages: &[Arc<ErrorPages>],
+	is_tls: bool,
+) -> Result<Router, Box<dyn Error>> {
+	match is_tls {
+		true => trace!("generating https router"),
+		false => trace!("generating http router"),
 	}
 
-	Some(scope)
+	let mut configured = Vec::new();
+	let mut routers = Vec::new();
+	for (vhost, error_pages) in vhosts.iter().zip(error_pages) {
+		if let Some(router) = configure_vhost_router(vhost, error_pages, is_tls)? {
+			configured.push(vhost);
+			routers.push(router);
+		}
+	}
+
+	Ok(Router {
+		hosts: HostMatcher::new(configured)?,
+		vhosts: routers,
+	})
 }
 
+impl Router {
+	fn handle(&self, req: ServiceRequest) -> ServiceResponse {
+		let host = request_host(req.head());
+		match self.hosts.find(host.as_deref()) {
+			Some(index) => {
+				let vhost = &self.vhosts[index];
+				vhost.error_page(vhost.handle(req))
+			}
+			None => req.into_response(handle_not_found()),
+		}
+	}
+}
+
+impl FilesRouter {
+	// Maps a path below the mount to a file, refusing hidden files and dot segments.
+	fn file_path(&self, path: &str) -> Option<PathBuf> {
+		let decoded = percent_decode_str(path).decode_utf8().ok()?;
+		let mut file = self.dir.to_owned();
+		for segment in decoded.split('/').filter(|segment| !segment.is_empty()) {
+			if segment.starts_with('.')
+				|| segment.starts_with('*')
+				|| segment.ends_with(&[':', '<', '>'][..])
+				|| segment.contains('\\')
+			{
+				return None;
+			}
+			file.push(segment);
+		}
+
+		Some(file)
+	}
+
+	// Serves a file the same way actix-files does, with index.html as the directory index.
+	fn serve(&self, req: ServiceRequest) -> ServiceResponse {
+		if !matches!(*req.method(), Method::GET | Method::HEAD) {
+			return req.into_response(HttpResponse::MethodNotAllowed().finish());
+		}
+
+		let file = match self.file_path(req.match_info().path()) {
+			Some(file) => file,
+			None => return req.into_response(HttpResponse::BadRequest().finish()),
+		};
+		let file = match file.canonicalize() {
+			Ok(file) if file.is_dir() => file.join("index.html"),
+			Ok(file) => file,
+			Err(err) => return req.error_response(err),
+		};
+
+		match NamedFile::open(&file) {
+			Ok(file) => {
+				let (request, _) = req.into_parts();
+				let response = file
+					.prefer_utf8(true)
+					.disable_content_disposition()
+					.into_response(&request);
+				ServiceResponse::new(request, response)
+			}
+			Err(err) => req.error_response(err),
+		}
+	}
+
+	// Redirects the request to its canonical URL, or rewrites it to an extension-less HTML file.
+	fn clean_url(&self, req: &mut ServiceRequest) -> Option<HttpResponse> {
+		if !self.resolve_html && self.trailing_slash == SlashPolicy::Ignore {
+			return None;
+		}
+
+		let path = req.path().to_owned();
+		let file = self.file_path(&path[self.mount.len()..])?;
+
+		let query = match req.query_string() {
+			"" => String::new(),
+			query => ["?", query].concat(),
+		};
+		let redirect = |dest: &str| {
+			debug!("redirecting {:?} to {:?}", path, dest);
+			Some(
+				HttpResponse::PermanentRedirect()
+					.append_header((header::LOCATION, [dest, &query].concat()))
+					.finish(),
+			)
+		};
+		let with_slash = |path: &str| match self.trailing_slash {
+			SlashPolicy::Strip if !path.is_empty() => path.to_owned(),
+			_ => [path, "/"].concat(),
+		};
+
+		if let Some(stem) = path.strip_suffix(".html").filter(|_| self.strip_html) {
+			if file.is_file() {
+				if let Some(dir) = stem.strip_suffix("/index") {
+					return redirect(&with_slash(dir));
+				}
+				if !file.with_extension("").exists() {
+					return match self.trailing_slash {
+						SlashPolicy::Add => redirect(&[stem, "/"].concat()),
+						_ => redirect(stem),
+					};
+				}
+			}
+		}
+
+		let mut html = file.to_owned().into_os_string();
+		html.push(".html");
+		let html = PathBuf::from(html);
+		let is_html = self.resolve_html && !file.exists() && html.is_file();
+		if !is_html && !file.exists() {
+			return None;
+		}
+
+		let trimmed = path.trim_end_matches('/');
+		match self.trailing_slash {
+			SlashPolicy::Add if !path.ends_with('/') && (is_html || file.is_dir()) => {
+				return redirect(&[&path, "/"].concat())
+			}
+			SlashPolicy::Strip if path.ends_with('/') && !trimmed.is_empty() => {
+				return redirect(trimmed)
+			}
+			_ => (),
+		}
+
+		if is_html {
+			let uri = Uri::from_str(&[trimmed, ".html", &query].concat()).ok()?;
+			trace!("resolving {:?} to {:?}", path, uri);
+			req.match_info_mut().get_mut().update(&uri);
+			req.head_mut().uri = uri;
+		}
+
+		None
+	}
+}
+
+impl VhostRouter {
+	fn handle(&self, mut req: ServiceRequest) -> ServiceResponse {
+		if let Some(dest) = &self.https_dest {
+			let response = handle_https_redirect(req.path(), dest);
+			return req.into_response(response);
+		}
+
+		if let Some(rules) = &self.client_rules {
+			let path = percent_decode_str(req.path()).decode_utf8_lossy();
+			let cert = req
+				.extensions()
+				.get::<ClientCert>()
+				.filter(|cert| cert.host == self.host)
+				.cloned();
+			if !rules.allows(&path, cert.as_ref()) {
+				debug!("client certificate {:?} denied access to {:?}", cert, path);
+				return req.into_response(handle_forbidden());
+			}
+		}
+
+		for (target, status, dest) in &self.redir {
+			let mut path = Path::new(req.path().to_owned());
+			if target.match_path(&mut path) {
+				return req.into_response(handle_redirect(&path, *status, dest));
+			}
+		}
+
+		for files in &self.files {
+			let path = req.path();
+			if path == files.mount || path.starts_with(&[&files.mount, "/"].concat()) {
+				if let Some(response) = files.clean_url(&mut req) {
+					return req.into_response(response);
+				}
+				req.match_info_mut().skip(files.mount.len() as u16);
+				return files.serve(req);
+			}
+		}
+
+		req.into_response(handle_not_found())
+	}
+
+	fn error_page(&self, response: ServiceResponse) -> ServiceResponse {
+		let status = response.status();
+		let (content_type, body) = match self.error_pages.get(&status) {
+			Some(page) => page,
+			None => return response,
+		};
+
+		let mut page = HttpResponse::build(status)
+			.content_type(content_type.as_str())
+			.body(body.to_owned());
+		// Other headers describe the original body, so only those describing the error are kept.
+		for key in &[header::ALLOW, header::RETRY_AFTER, header::WWW_AUTHENTICATE] {
+			for val in response.headers().get_all(key) {
+				page.headers_mut().append(key.to_owned(), val.to_owned());
+			}
+		}
+
+		response.into_response(page)
+	}
+}
+
+async fn handle_request(
+	req: ServiceRequest,
+	table: RoutingTable,
+	challenges: Arc<Challenges>,
+	is_tls: bool,
+) -> Result<ServiceResponse, actix_web::Error> {
+	if let Some(key_authorization) = challenges.http(req.path()) {
+		debug!("answering http-01 challenge for {:?}", req.path());
+		let response = HttpResponse::Ok()
+			.content_type("application/octet-stream")
+			.body(key_authorization);
+		return Ok(req.into_response(response));
+	}
+
+	let routes = table.load();
+	let router = match is_tls {
+		true => &routes.https,
+		false => &routes.http,
+	};
+
+	let mut response = router.handle(req);
+	for (key, val) in &routes.headers {
+		if !response.headers().contains_key(key) {
+			response
+				.headers_mut()
+				.insert(key.to_owned(), val.to_owned());
+		}
+	}
+
+	Ok(response)
+}
+
+// Listener settings shared by the HTTP and HTTPS servers, which match HttpServer's defaults.
+// Workers and max connections are left at actix-server's defaults for both.
+const KEEP_ALIVE: usize = 5;
+const CLIENT_TIMEOUT: u64 = 5000;
+const CLIENT_SHUTDOWN: u64 = 5000;
+const BACKLOG: u32 = 1024;
+
 pub fn run_http_server(
 	is_tls: bool,
 	server: &Server,
-	headers: &Headers,
-	vhosts: &[Vhost],
-) -> Result<impl Future<Output = Result<(), std::io::Error>>, Box<dyn Error>> {
+	table: &RoutingTable,
+	resolver: Option<&Arc<CertResolver>>,
+	challenges: &Arc<Challenges>,
+) -> Result<Option<dev::Server>, Box<dyn Error>> {
 	let log_format = server.log_format.to_owned();
-	let vhosts_copy = vhosts.to_owned();
-	let headers_copy = headers.to_owned();
+	let table = table.to_owned();
+	let challenges = challenges.to_owned();
 
-	let mut http_server = HttpServer::new(move || {
+	let app = move || {
 		match is_tls {
 			true => trace!("generating https application builder"),
 			false => trace!("generating http application builder"),
 		}
 
-		let mut default_headers = DefaultHeaders::new();
-		for (key, val) in &headers_copy {
-			default_headers = default_headers.header(key, val);
-		}
-
-		let mut app = App::new()
-			.wrap(Logger::new(&log_format))
-			.wrap(default_headers)
+		let table = table.to_owned();
+		let challenges = challenges.to_owned();
+
+		App::new()
+			.wrap(
+				Logger::new(&log_format)
+					.custom_request_replace("client_subject", |req| {
+						req.extensions()
+							.get::<ClientCert>()
+							.map_or_else(|| "-".to_string(), |cert| cert.subject.to_owned())
+					})
+					.custom_request_replace("client_serial", |req| {
+						req.extensions()
+							.get::<ClientCert>()
+							.map_or_else(|| "-".to_string(), |cert| cert.serial.to_owned())
+					}),
+			)
 			.wrap(NormalizePath::new(TrailingSlash::MergeOnly))
 			.wrap(Compress::default())
-			.default_service(web::route().to(handle_not_found));
-
-		for vhost in &vhosts_copy {
-			app = match configure_vhost_scope(&vhost, is_tls) {
-				Some(scope) => app.service(scope),
-				None => app,
-			};
-		}
-
-		app
-	});
+			.default_service(fn_service(move |req: ServiceRequest| {
+				handle_request(req, table.to_owned(), challenges.to_owned(), is_tls)
+			}))
+	};
 
 	match is_tls {
 		true => {
 			if server.tls_bind.is_empty() {
 				debug!("tls_bind is empty, skipping https init");
-				return Ok(Either::Left(future::ready(Ok(()))));
+				return Ok(None);
 			}
 			info!("Starting HTTPS Server");
 
-			debug!("loading tls certificates");
-			let mut resolver = ResolvesServerCertUsingSNI::new();
-			for vhost in vhosts {
-				if let Some(tls) = &vhost.tls {
-					let keypair = create_certified_key(&tls.pemfiles)?;
-					resolver.add(&vhost.host, keypair)?;
-				}
+			let resolver = resolver.ok_or("no tls certificates loaded")?.to_owned();
+			let tlsconf = tls::server_config(&server.tls, resolver.to_owned())?;
+
+			// actix-web's own rustls listener overrides the configured ALPN protocols,
+			// which would break tls-alpn-01 challenges, so HTTPS is served by the same
+			// pipeline HttpServer builds, using the same listener settings as HTTP.
+			let mut https_server = actix_server::Server::build().backlog(BACKLOG);
+			for addr in server.tls_bind.iter().copied() {
+				let app = app.to_owned();
+				let tlsconf = tlsconf.to_owned();
+				let resolver = resolver.to_owned();
+				let name = format!("katwebsite-https-{}", addr);
+
+				https_server = https_server.bind(name, addr, move || {
+					pipeline_factory(
+						Acceptor::new(tlsconf.to_owned())
+							.map_err(TlsError::Tls)
+							.map_init_err(|_| ()),
+					)
+					.and_then(|io: TlsStream<TcpStream>| async move {
+						let peer_addr = io.get_ref().0.peer_addr().ok();
+						let protocol = match io.get_ref().1.get_alpn_protocol() {
+							Some(b"h2") => Protocol::Http2,
+							Some(ACME_TLS_ALPN) => {
+								trace!("closing tls-alpn-01 validation connection");
+								return Err(TlsError::Tls(io::ErrorKind::ConnectionAborted.into()));
+							}
+							_ => Protocol::Http1,
+						};
+						Ok((io, protocol, peer_addr))
+					})
+					.and_then(
+						HttpService::build()
+							.keep_alive(KeepAlive::Timeout(KEEP_ALIVE))
+							.client_timeout(CLIENT_TIMEOUT)
+							.client_disconnect(CLIENT_SHUTDOWN)
+							.secure()
+							.local_addr(addr)
+							.on_connect_ext({
+								let resolver = resolver.to_owned();
+								move |io: &TlsStream<TcpStream>, extensions: &mut Extensions| {
+									if let Some(cert) = resolver.client_cert(io.get_ref().1) {
+										extensions.insert(cert);
+									}
+								}
+							})
+							// The App's config is only used by actix-web for URL generation and
+							// ConnectionInfo fallbacks, which KatWebSite doesn't rely on.
+							.finish(map_config(app(), |_| AppConfig::default()))
+							.map_err(TlsError::Service),
+					)
+				})?;
 			}
 
-			let mut tlsconf = ServerConfig::new(NoClientAuth::new());
-			tlsconf.cert_resolver = Arc::new(resolver);
-
-			for addr in &server.tls_bind {
-				http_server = http_server.bind_rustls(addr, tlsconf.to_owned())?
-			}
+			Ok(Some(https_server.disable_signals().run()))
 		}
 		false => {
 			if server.http_bind.is_empty() {
 				debug!("http_bind is empty, skipping http init");
-				return Ok(Either::Left(future::ready(Ok(()))));
+				return Ok(None);
 			}
 			info!("Starting HTTP Server");
 
+			let mut http_server = HttpServer::new(app)
+				.keep_alive(KeepAlive::Timeout(KEEP_ALIVE))
+				.client_timeout(CLIENT_TIMEOUT)
+				.client_shutdown(CLIENT_SHUTDOWN)
+				.backlog(BACKLOG);
 			for addr in &server.http_bind {
 				http_server = http_server.bind(addr)?
 			}
+
+			Ok(Some(http_server.disable_signals().run()))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn client_rules(required: bool) -> ClientRules {
+		ClientRules {
+			required,
+			access: vec![
+				(
+					"/private".to_string(),
+					vec![glob::Pattern::new("CN=alice*").unwrap()],
+				),
+				("/members".to_string(), vec![]),
+			],
 		}
 	}
 
-	Ok(Either::Right(http_server.run()))
+	fn client_cert(subject: &str) -> ClientCert {
+		ClientCert {
+			host: "localhost".to_string(),
+			subject: subject.to_string(),
+			serial: "01".to_string(),
+		}
+	}
+
+	fn files_router(name: &str, trailing_slash: SlashPolicy, strip_html: bool) -> FilesRouter {
+		let dir =
+			std::env::temp_dir().join(format!("katwebsite-http-{}-{}", std::process::id(), name));
+		fs::create_dir_all(dir.join("dir")).unwrap();
+		for file in &["index.html", "foo.html", "dir/index.html"] {
+			fs::write(dir.join(file), "").unwrap();
+		}
+
+		FilesRouter {
+			mount: String::new(),
+			dir,
+			resolve_html: true,
+			strip_html,
+			trailing_slash,
+		}
+	}
+
+	// Returns the redirect destination, or the path the request was rewritten to.
+	fn clean_url(files: &FilesRouter, uri: &str) -> Result<String, String> {
+		let mut req = actix_web::test::TestRequest::with_uri(uri).to_srv_request();
+		match files.clean_url(&mut req) {
+			Some(response) => Ok(response
+				.headers()
+				.get(header::LOCATION)
+				.unwrap()
+				.to_str()
+				.unwrap()
+				.to_owned()),
+			None => Err(req.uri().to_string()),
+		}
+	}
+
+	#[test]
+	fn clean_url_strips_html_extensions() {
+		let files = files_router("strip-html", SlashPolicy::Ignore, true);
+
+		assert_eq!(clean_url(&files, "/index.html"), Ok("/".to_string()));
+		assert_eq!(clean_url(&files, "/foo.html"), Ok("/foo".to_string()));
+		assert_eq!(
+			clean_url(&files, "/foo.html?a=b"),
+			Ok("/foo?a=b".to_string())
+		);
+		assert_eq!(
+			clean_url(&files, "/dir/index.html"),
+			Ok("/dir/".to_string())
+		);
+		assert_eq!(clean_url(&files, "/foo"), Err("/foo.html".to_string()));
+		assert_eq!(clean_url(&files, "/dir"), Err("/dir".to_string()));
+		assert_eq!(
+			clean_url(&files, "/missing.html"),
+			Err("/missing.html".to_string())
+		);
+
+		fs::remove_dir_all(&files.dir).unwrap();
+	}
+
+	#[test]
+	fn clean_url_adds_trailing_slashes() {
+		let files = files_router("add-slash", SlashPolicy::Add, false);
+
+		assert_eq!(clean_url(&files, "/dir"), Ok("/dir/".to_string()));
+		assert_eq!(clean_url(&files, "/foo"), Ok("/foo/".to_string()));
+		assert_eq!(clean_url(&files, "/foo/"), Err("/foo.html".to_string()));
+		assert_eq!(
+			clean_url(&files, "/index.html"),
+			Err("/index.html".to_string())
+		);
+		assert_eq!(clean_url(&files, "/"), Err("/".to_string()));
+
+		fs::remove_dir_all(&files.dir).unwrap();
+	}
+
+	#[test]
+	fn clean_url_strips_trailing_slashes() {
+		let files = files_router("strip-slash", SlashPolicy::Strip, true);
+
+		assert_eq!(clean_url(&files, "/dir/"), Ok("/dir".to_string()));
+		assert_eq!(clean_url(&files, "/dir/index.html"), Ok("/dir".to_string()));
+		assert_eq!(clean_url(&files, "/foo/"), Ok("/foo".to_string()));
+		assert_eq!(clean_url(&files, "/"), Err("/".to_string()));
+		assert_eq!(clean_url(&files, "/missing/"), Err("/missing/".to_string()));
+
+		fs::remove_dir_all(&files.dir).unwrap();
+	}
+
+	#[test]
+	fn client_rules_refuse_dot_segments() {
+		let rules = client_rules(false);
+		let alice = client_cert("CN=alice");
+
+		assert!(!rules.allows("/public/../private/file", None));
+		assert!(!rules.allows("/private/../private/file", Some(&alice)));
+		assert!(!rules.allows("/..", None));
+		assert!(rules.allows("/public/..file", None));
+	}
+
+	#[test]
+	fn client_rules_match_path_prefixes() {
+		let rules = client_rules(false);
+		let alice = client_cert("CN=alice");
+		let bob = client_cert("CN=bob");
+
+		assert!(rules.allows("/public", None));
+		assert!(rules.allows("/privateer", None));
+		assert!(!rules.allows("/private", None));
+		assert!(!rules.allows("/private/file", Some(&bob)));
+		assert!(rules.allows("/private/file", Some(&alice)));
+		assert!(rules.allows("/members/file", Some(&bob)));
+		assert!(!rules.allows("/members/file", None));
+	}
+
+	#[test]
+	fn client_rules_can_require_a_certificate() {
+		let rules = client_rules(true);
+
+		assert!(!rules.allows("/public", None));
+		assert!(rules.allows("/public", Some(&client_cert("CN=bob"))));
+	}
 }
@@ -0,0 +1,115 @@
+#![warn(clippy::all)]
+
+use liquid_core::{
+	model::ScalarCow, parser::FilterArguments, Display_filter, Error, Expression, Filter,
+	FilterReflection, ParseFilter, Result, Runtime, Value, ValueView,
+};
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct I18n {
+	#[serde(default)]
+	pub languages: Vec<String>,
+}
+
+impl I18n {
+	pub fn is_enabled(&self) -> bool {
+		!self.languages.is_empty()
+	}
+
+	pub fn default_language(&self) -> Option<&String> {
+		self.languages.first()
+	}
+
+	pub fn split_language(&self, path: &Path) -> (Option<String>, PathBuf) {
+		let stem = Path::new(path.file_stem().unwrap_or_default());
+		if let Some(lang) = stem.extension().and_then(|lang| lang.to_str()) {
+			if self.languages.iter().any(|l| l == lang) {
+				let mut key = stem.with_extension("");
+				if let Some(ext) = path.extension() {
+					key.set_extension(ext);
+				}
+				return (Some(lang.to_owned()), path.with_file_name(key));
+			}
+		}
+
+		(None, path.to_owned())
+	}
+}
+
+#[derive(Clone, FilterReflection)]
+#[filter(
+	name = "t",
+	description = "Translates a key into the current page's language."
+)]
+pub struct Translate;
+
+impl ParseFilter for Translate {
+	fn parse(&self, mut arguments: FilterArguments) -> Result<Box<dyn Filter>> {
+		let lang = arguments.positional.next();
+		if arguments.positional.next().is_some() {
+			return Err(Error::with_msg("t: expected at most one argument"));
+		}
+		if let Some((key, _)) = arguments.keyword.next() {
+			return Err(Error::with_msg(format!("t: unexpected argument {:?}", key)));
+		}
+
+		Ok(Box::new(TranslateFilter { lang }))
+	}
+
+	fn reflection(&self) -> &dyn FilterReflection {
+		self
+	}
+}
+
+#[derive(Debug, Display_filter)]
+#[name = "t"]
+struct TranslateFilter {
+	lang: Option<Expression>,
+}
+
+impl Filter for TranslateFilter {
+	fn evaluate(&self, input: &dyn ValueView, runtime: &dyn Runtime) -> Result<Value> {
+		let key = input.to_kstr();
+
+		let mut languages = Vec::new();
+		match &self.lang {
+			Some(lang) => languages.push(lang.evaluate(runtime)?.to_kstr().to_string()),
+			None => {
+				let path = [
+					ScalarCow::new("page"),
+					ScalarCow::new("data"),
+					ScalarCow::new("lang"),
+				];
+				if let Some(lang) = runtime.try_get(&path) {
+					languages.push(lang.to_kstr().to_string());
+				}
+			}
+		}
+		let path = [
+			ScalarCow::new("site"),
+			ScalarCow::new("languages"),
+			ScalarCow::new(0),
+		];
+		if let Some(lang) = runtime.try_get(&path) {
+			languages.push(lang.to_kstr().to_string());
+		}
+
+		for lang in &languages {
+			let mut path = vec![
+				ScalarCow::new("site"),
+				ScalarCow::new("i18n"),
+				ScalarCow::new(lang.as_str()),
+			];
+			path.extend(key.split('.').map(ScalarCow::new));
+
+			if let Some(translation) = runtime.try_get(&path) {
+				return Ok(translation.to_value());
+			}
+		}
+
+		Ok(Value::scalar(key.into_owned()))
+	}
+}
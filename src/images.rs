@@ -0,0 +1,331 @@
+#![warn(clippy::all)]
+
+use image::{
+	codecs::{avif::AvifEncoder, jpeg::JpegEncoder},
+	error::{EncodingError, ImageFormatHint},
+	imageops::FilterType,
+	DynamicImage, ImageError, ImageFormat, ImageResult,
+};
+use liquid_core::{
+	model::{ObjectView, ScalarCow},
+	parser::FilterArguments,
+	Display_filter, Error, Expression, Filter, FilterReflection, ParseFilter, Runtime, Value,
+	ValueView,
+};
+use log::trace;
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, io::Cursor, path::Path};
+
+pub type Manifest = BTreeMap<String, Image>;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Image {
+	pub src: String,
+	pub width: u32,
+	pub height: u32,
+
+	#[serde(rename = "type")]
+	pub mime_type: String,
+	pub srcset: String,
+	pub sources: Vec<Source>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Source {
+	#[serde(rename = "type")]
+	pub mime_type: String,
+	pub srcset: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Images {
+	#[serde(default = "default_images_extensions")]
+	pub extensions: Vec<String>,
+
+	#[serde(default = "default_images_widths")]
+	pub widths: Vec<u32>,
+
+	#[serde(default = "default_images_formats")]
+	pub formats: Vec<String>,
+
+	#[serde(default = "default_images_quality")]
+	pub quality: u8,
+}
+
+fn default_images_extensions() -> Vec<String> {
+	vec![
+		"jpg".to_string(),
+		"jpeg".to_string(),
+		"png".to_string(),
+		"gif".to_string(),
+		"webp".to_string(),
+	]
+}
+
+fn default_images_widths() -> Vec<u32> {
+	vec![480, 960, 1920]
+}
+
+fn default_images_formats() -> Vec<String> {
+	vec!["webp".to_string()]
+}
+
+fn default_images_quality() -> u8 {
+	80
+}
+
+impl Default for Images {
+	fn default() -> Self {
+		Images {
+			extensions: default_images_extensions(),
+			widths: default_images_widths(),
+			formats: default_images_formats(),
+			quality: default_images_quality(),
+		}
+	}
+}
+
+impl Images {
+	pub fn matches(&self, path: &Path) -> bool {
+		match path.extension().unwrap_or_default().to_str() {
+			Some(ext) => self.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+			None => false,
+		}
+	}
+
+	fn encode(&self, image: &DynamicImage, format: ImageFormat) -> ImageResult<Vec<u8>> {
+		let mut output = Vec::new();
+		match format {
+			ImageFormat::WebP => {
+				let image = image.to_rgba8();
+				let encoded = webp::Encoder::from_rgba(&image, image.width(), image.height())
+					.encode_simple(false, self.quality.into())
+					.map_err(|err| {
+						ImageError::Encoding(EncodingError::new(
+							ImageFormatHint::Exact(ImageFormat::WebP),
+							format!("{:?}", err),
+						))
+					})?;
+				output.extend_from_slice(&encoded);
+			}
+			ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+				.write_with_encoder(JpegEncoder::new_with_quality(&mut output, self.quality))?,
+			ImageFormat::Avif => DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(
+				AvifEncoder::new_with_speed_quality(&mut output, 8, self.quality),
+			)?,
+			_ => image.write_to(Cursor::new(&mut output), format)?,
+		}
+
+		Ok(output)
+	}
+
+	pub fn render(&self, output_dir: &Path, path: &Path) -> Result<Image, String> {
+		let input_file = output_dir.join(path);
+		let original = image::open(&input_file).map_err(|err| err.to_string())?;
+		let original_format = ImageFormat::from_path(path).map_err(|err| err.to_string())?;
+
+		let mut widths = self
+			.widths
+			.iter()
+			.copied()
+			.filter(|width| *width > 0 && *width < original.width())
+			.collect::<Vec<_>>();
+		widths.push(original.width());
+		widths.sort_unstable();
+		widths.dedup();
+
+		let mut formats = vec![original_format];
+		for format in &self.formats {
+			match ImageFormat::from_extension(format) {
+				Some(format) if format != original_format => formats.push(format),
+				Some(_) => (),
+				None => return Err(format!("unknown image format {:?}", format)),
+			}
+		}
+
+		let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+		let mut srcsets = vec![Vec::new(); formats.len()];
+		for width in widths {
+			let resized = match width == original.width() {
+				true => original.to_owned(),
+				false => original.resize(width, u32::MAX, FilterType::Lanczos3),
+			};
+
+			for (format, srcset) in formats.iter().zip(srcsets.iter_mut()) {
+				let variant = match (width == original.width(), format == &original_format) {
+					(true, true) => path.to_owned(),
+					_ => path.with_file_name(
+						[
+							&stem,
+							"-",
+							&width.to_string(),
+							"w.",
+							format.extensions_str().first().unwrap_or(&""),
+						]
+						.concat(),
+					),
+				};
+
+				if variant != path {
+					trace!("writing {:?}", &variant);
+					let content = self
+						.encode(&resized, *format)
+						.map_err(|err| err.to_string())?;
+					fs::write(output_dir.join(&variant), content).map_err(|err| err.to_string())?;
+				}
+
+				srcset.push([&variant.to_string_lossy(), " ", &width.to_string(), "w"].concat());
+			}
+		}
+
+		let mut sources = formats
+			.iter()
+			.zip(srcsets)
+			.map(|(format, srcset)| Source {
+				mime_type: format.to_mime_type().to_string(),
+				srcset: srcset.join(", "),
+			})
+			.collect::<Vec<_>>();
+		let original_source = sources.remove(0);
+
+		Ok(Image {
+			src: path.to_string_lossy().to_string(),
+			width: original.width(),
+			height: original.height(),
+			mime_type: original_source.mime_type,
+			srcset: original_source.srcset,
+			sources,
+		})
+	}
+}
+
+fn escape(input: &str) -> String {
+	input
+		.replace('&', "&amp;")
+		.replace('"', "&quot;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+}
+
+#[derive(Clone, FilterReflection)]
+#[filter(
+	name = "image",
+	description = "Converts an image's filename into responsive picture markup."
+)]
+pub struct Picture;
+
+impl ParseFilter for Picture {
+	fn parse(&self, mut arguments: FilterArguments) -> liquid_core::Result<Box<dyn Filter>> {
+		let alt = arguments.positional.next();
+		if arguments.positional.next().is_some() {
+			return Err(Error::with_msg("image: expected at most one argument"));
+		}
+		let mut sizes = None;
+		for (key, value) in arguments.keyword {
+			match key {
+				"sizes" => sizes = Some(value),
+				_ => {
+					return Err(Error::with_msg(format!(
+						"image: unexpected argument {:?}",
+						key
+					)))
+				}
+			}
+		}
+
+		Ok(Box::new(ImageFilter { alt, sizes }))
+	}
+
+	fn reflection(&self) -> &dyn FilterReflection {
+		self
+	}
+}
+
+#[derive(Debug, Display_filter)]
+#[name = "image"]
+struct ImageFilter {
+	alt: Option<Expression>,
+	sizes: Option<Expression>,
+}
+
+impl Filter for ImageFilter {
+	fn evaluate(&self, input: &dyn ValueView, runtime: &dyn Runtime) -> liquid_core::Result<Value> {
+		let name = input.to_kstr();
+		let alt = match &self.alt {
+			Some(alt) => escape(&alt.evaluate(runtime)?.to_kstr()),
+			None => String::new(),
+		};
+		let sizes = match &self.sizes {
+			Some(sizes) => [
+				" sizes=\"",
+				&escape(&sizes.evaluate(runtime)?.to_kstr()),
+				"\"",
+			]
+			.concat(),
+			None => String::new(),
+		};
+
+		let path = [
+			ScalarCow::new("site"),
+			ScalarCow::new("images"),
+			ScalarCow::new(name.as_str()),
+		];
+		let image = match runtime.try_get(&path) {
+			Some(image) => image,
+			None => {
+				return Ok(Value::scalar(
+					["<img src=\"", &escape(&name), "\" alt=\"", &alt, "\">"].concat(),
+				))
+			}
+		};
+		let image = match image.as_object() {
+			Some(image) => image,
+			None => return Ok(Value::Nil),
+		};
+		let field = |object: &dyn ObjectView, key: &str| {
+			object
+				.get(key)
+				.map(|value| escape(&value.to_kstr()))
+				.unwrap_or_default()
+		};
+
+		let mut output = String::from("<picture>");
+		if let Some(sources) = image.get("sources").and_then(|s| s.as_array()) {
+			for source in sources.values().filter_map(|s| s.as_object()) {
+				output.push_str(
+					&[
+						"<source type=\"",
+						&field(source, "type"),
+						"\" srcset=\"",
+						&field(source, "srcset"),
+						"\"",
+						&sizes,
+						">",
+					]
+					.concat(),
+				);
+			}
+		}
+		output.push_str(
+			&[
+				"<img src=\"",
+				&field(image, "src"),
+				"\" srcset=\"",
+				&field(image, "srcset"),
+				"\"",
+				&sizes,
+				" width=\"",
+				&field(image, "width"),
+				"\" height=\"",
+				&field(image, "height"),
+				"\" alt=\"",
+				&alt,
+				"\" loading=\"lazy\" decoding=\"async\"></picture>",
+			]
+			.concat(),
+		);
+
+		Ok(Value::scalar(output))
+	}
+}
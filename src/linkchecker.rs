@@ -0,0 +1,218 @@
+#![warn(clippy::all)]
+
+use crate::html::{self, Token};
+use log::{debug, trace, warn};
+use percent_encoding::percent_decode_str;
+use rayon::prelude::*;
+use serde_derive::Deserialize;
+use std::{
+	collections::{BTreeMap, HashSet},
+	fs,
+	path::{Component, Path, PathBuf},
+	sync::RwLock,
+};
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LinkChecker {
+	#[serde(default)]
+	pub fail: bool,
+
+	#[serde(default)]
+	pub ignore: Vec<String>,
+}
+
+struct Link {
+	target: String,
+	line: u64,
+}
+
+fn read_links(input: &str) -> (Vec<Link>, HashSet<String>) {
+	let mut links = Vec::new();
+	let mut ids = HashSet::new();
+
+	for token in html::tokenize(input) {
+		if let Token::Start { name, line, .. } = &token {
+			if let Some(id) = token.attr("id") {
+				ids.insert(id.to_owned());
+			}
+			if let ("a", Some(id)) = (name.as_str(), token.attr("name")) {
+				ids.insert(id.to_owned());
+			}
+
+			let attrs: &[&str] = match name.as_ref() {
+				"a" | "area" | "link" => &["href"],
+				"img" | "source" => &["src", "srcset"],
+				"script" | "iframe" | "embed" | "track" | "audio" => &["src"],
+				"video" => &["src", "poster"],
+				_ => &[],
+			};
+			for attr in attrs {
+				match (*attr, token.attr(attr)) {
+					("srcset", Some(srcset)) => {
+						links.extend(srcset.split(',').filter_map(|candidate| {
+							Some(Link {
+								target: candidate.split_whitespace().next()?.to_owned(),
+								line: *line,
+							})
+						}))
+					}
+					(_, Some(target)) => links.push(Link {
+						target: target.trim().to_owned(),
+						line: *line,
+					}),
+					_ => (),
+				}
+			}
+		}
+	}
+
+	(links, ids)
+}
+
+pub fn is_external(target: &str) -> bool {
+	if target.starts_with("//") {
+		return true;
+	}
+
+	match target.find(':') {
+		Some(index) => !target[..index].contains(&['/', '?', '#'][..]),
+		None => false,
+	}
+}
+
+pub fn resolve_path(root: &Path, page: &Path, target: &str) -> Option<PathBuf> {
+	let target = percent_decode_str(target).decode_utf8().ok()?;
+
+	let base = match target.starts_with('/') {
+		true => PathBuf::new(),
+		false => page.parent().map(Path::to_path_buf).unwrap_or_default(),
+	};
+
+	let mut resolved = PathBuf::new();
+	for component in base.join(target.trim_start_matches('/')).components() {
+		match component {
+			Component::Normal(part) => resolved.push(part),
+			Component::ParentDir if !resolved.pop() => return None,
+			_ => (),
+		}
+	}
+
+	let resolved = root.join(resolved);
+	match resolved.is_dir() {
+		true => Some(resolved.join("index.html")),
+		false => Some(resolved),
+	}
+}
+
+// Finds the line of the input file that a link was written on, which isn't possible for links
+// that were generated by a layout, include or Renderer.
+fn source_line(source: &str, target: &str) -> Option<usize> {
+	let decoded = percent_decode_str(target).decode_utf8_lossy();
+	source
+		.lines()
+		.position(|line| line.contains(target) || line.contains(decoded.as_ref()))
+		.map(|index| index + 1)
+}
+
+pub fn run_link_checker(
+	input: &Path,
+	output: &Path,
+	pages: &[(PathBuf, PathBuf)],
+	checker: &LinkChecker,
+) -> usize {
+	let ids: RwLock<BTreeMap<PathBuf, HashSet<String>>> = RwLock::new(BTreeMap::new());
+
+	let read_ids = |path: &Path| -> Option<HashSet<String>> {
+		if let Some(ids) = ids.read().ok()?.get(path) {
+			return Some(ids.to_owned());
+		}
+		let (_, found) = read_links(&fs::read_to_string(path).ok()?);
+		ids.write()
+			.ok()?
+			.insert(path.to_path_buf(), found.to_owned());
+
+		Some(found)
+	};
+
+	pages
+		.par_iter()
+		.map(|(path, source)| {
+			let file = output.join(path);
+			let content = match fs::read_to_string(&file) {
+				Ok(content) => content,
+				Err(err) => {
+					debug!("Unable to read {:?}! {}", &file, err);
+					return 0;
+				}
+			};
+
+			trace!("checking links in {:?}", path);
+			let source_file = input.join(source);
+			let source_content = fs::read_to_string(&source_file).unwrap_or_default();
+			let (links, page_ids) = read_links(&content);
+			if let Ok(mut ids) = ids.write() {
+				ids.insert(file.to_owned(), page_ids);
+			}
+
+			let mut broken = 0;
+			for link in links {
+				if link.target.is_empty()
+					|| is_external(&link.target)
+					|| checker
+						.ignore
+						.iter()
+						.any(|prefix| link.target.starts_with(prefix))
+				{
+					continue;
+				}
+
+				let (target, fragment) = match link.target.split_once('#') {
+					Some((target, fragment)) => (target, Some(fragment)),
+					None => (link.target.as_str(), None),
+				};
+				let target = target.split('?').next().unwrap_or_default();
+
+				let target_file = match target.is_empty() {
+					true => Some(file.to_owned()),
+					false => resolve_path(output, path, target),
+				};
+
+				let error = match &target_file {
+					Some(target_file) if target_file.is_file() => match fragment {
+						Some(fragment)
+							if !fragment.is_empty()
+								&& fragment != "top" && target_file
+								.extension()
+								.unwrap_or_default() == "html" =>
+						{
+							let fragment = percent_decode_str(fragment).decode_utf8_lossy();
+							match read_ids(target_file) {
+								Some(ids) if ids.contains(fragment.as_ref()) => None,
+								_ => Some("missing anchor"),
+							}
+						}
+						_ => None,
+					},
+					_ => Some("missing file"),
+				};
+
+				if let Some(error) = error {
+					match source_line(&source_content, &link.target) {
+						Some(line) => warn!(
+							"Broken link to {:?} in {:?} line {}: {}",
+							&link.target, &source_file, line, error
+						),
+						None => warn!(
+							"Broken link to {:?} in {:?} (generated at {:?} line {}): {}",
+							&link.target, &source_file, path, link.line, error
+						),
+					}
+					broken += 1;
+				}
+			}
+
+			broken
+		})
+		.sum()
+}
@@ -1,19 +1,42 @@
 #![warn(clippy::all)]
 
+use actix_web::dev::Server;
 use clap::Clap;
-use futures::try_join;
+use futures::future::{self, Future};
 use log::{debug, error, info, trace, warn};
 use mimalloc::MiMalloc;
 use serde_derive::Deserialize;
-use std::{env, fs, path::PathBuf, process};
+use std::{
+	env,
+	error::Error,
+	fs,
+	path::{Path, PathBuf},
+	pin::Pin,
+	process,
+	sync::Arc,
+	time::{Duration, SystemTime},
+};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+mod acme;
+mod assets;
 mod builder;
 mod copier;
+mod hosts;
+mod html;
 mod http;
+mod i18n;
+mod images;
+mod linkchecker;
+mod markdown;
+mod plugins;
+mod related;
 mod runner;
+mod selfsigned;
+mod shortcodes;
+mod tls;
 
 /// A minimal static site generator and web server.
 #[derive(Clap, Debug)]
@@ -30,6 +53,18 @@ struct Opts {
 	/// Increases log verbosity, ignored if RUST_LOG is set. Maximum possible log verbosity is 3.
 	#[clap(short, long, parse(from_occurrences))]
 	verbose: i32,
+
+	#[clap(subcommand)]
+	command: Option<Command>,
+}
+
+#[derive(Clap, Debug)]
+enum Command {
+	/// Generates self-signed TLS certificates, without starting the server.
+	SelfSigned {
+		/// The hosts to generate certificates for. Defaults to all vhosts with self_signed enabled.
+		hosts: Vec<String>,
+	},
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -60,6 +95,192 @@ struct Config {
 	server: http::Server,
 }
 
+fn reload_config(
+	config_file: &Path,
+	server: &http::Server,
+	table: &http::RoutingTable,
+	resolver: Option<&tls::CertResolver>,
+	acme: Option<&acme::Manager>,
+) -> Result<(), Box<dyn Error>> {
+	debug!("parsing {:?} as toml configuration", config_file);
+	let config: Config = toml::from_str(&fs::read_to_string(config_file)?)?;
+	let headers = http::load_header_files(&config.server, &config.headers)?;
+	let routes = http::RoutingTable::prepare(&config.vhost, &headers)?;
+	let certs = match resolver {
+		Some(resolver) => Some(resolver.prepare(&config.vhost)?),
+		None => None,
+	};
+
+	table.commit(routes);
+	if let (Some(resolver), Some(certs)) = (resolver, certs) {
+		resolver.commit(certs);
+	}
+	if let Some(acme) = acme {
+		acme.update(&config.vhost);
+	}
+
+	// header_files are reloaded, but the rest of the [server] block is only read on startup.
+	let reloaded = http::Server {
+		header_files: server.header_files.to_owned(),
+		..config.server
+	};
+	if reloaded != *server {
+		warn!("Changes to the [server] block can't be reloaded, restart KatWebSite to apply them!");
+	}
+
+	Ok(())
+}
+
+fn generate_self_signed(config: &Config, hosts: &[String]) {
+	let hosts: Vec<Vec<String>> = match hosts.is_empty() {
+		true => config
+			.vhost
+			.iter()
+			.filter(|vhost| {
+				vhost
+					.tls
+					.as_ref()
+					.map(|tls| tls.self_signed)
+					.unwrap_or(false)
+			})
+			.map(|vhost| vhost.names().cloned().collect())
+			.collect(),
+		false => hosts.iter().map(|host| vec![host.to_owned()]).collect(),
+	};
+	if hosts.is_empty() {
+		error!("No hosts specified, and no vhosts have self_signed enabled!");
+		process::exit(exitcode::USAGE);
+	}
+
+	let dir = &config.server.tls.self_signed_dir;
+	for names in &hosts {
+		let pemfiles = selfsigned::generate(dir, names).unwrap_or_else(|err| {
+			error!("Unable to generate certificate for {:?}! {}", names[0], err);
+			process::exit(exitcode::CANTCREAT);
+		});
+		info!("Certificate for {:?} is stored in {:?}", names[0], pemfiles);
+	}
+	info!(
+		"Certificates are issued by {:?}, add it to your trusted root certificates to avoid browser warnings",
+		selfsigned::ca_file(dir)
+	);
+}
+
+fn modified(config_file: &Path) -> Option<SystemTime> {
+	fs::metadata(config_file)
+		.and_then(|metadata| metadata.modified())
+		.ok()
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Event {
+	Reload,
+	Poll,
+	RefreshCerts,
+	Stop { graceful: bool },
+}
+
+async fn handle_events(
+	config_file: PathBuf,
+	table: http::RoutingTable,
+	resolver: Option<Arc<tls::CertResolver>>,
+	acme: Option<acme::Manager>,
+	server: http::Server,
+	servers: Vec<Server>,
+) {
+	#[cfg(unix)]
+	let mut signals = {
+		use actix_rt::signal::unix::{signal, SignalKind};
+
+		[
+			(SignalKind::hangup(), Event::Reload),
+			(SignalKind::interrupt(), Event::Stop { graceful: false }),
+			(SignalKind::terminate(), Event::Stop { graceful: true }),
+			(SignalKind::quit(), Event::Stop { graceful: false }),
+		]
+		.iter()
+		.filter_map(|(kind, event)| match signal(*kind) {
+			Ok(signal) => Some((signal, *event)),
+			Err(err) => {
+				warn!("Unable to listen for {:?}! {}", kind, err);
+				None
+			}
+		})
+		.collect::<Vec<_>>()
+	};
+	let mut interval = actix_rt::time::interval(Duration::from_secs(2));
+	let mut tls_interval =
+		actix_rt::time::interval(Duration::from_secs(server.tls_reload_interval.max(1)));
+	tls_interval.tick().await;
+	let mut last_modified = modified(&config_file);
+
+	loop {
+		let mut events: Vec<Pin<Box<dyn Future<Output = Event> + '_>>> = Vec::new();
+		#[cfg(unix)]
+		for (signal, event) in signals.iter_mut() {
+			events.push(Box::pin(async move {
+				signal.recv().await;
+				*event
+			}));
+		}
+		#[cfg(not(unix))]
+		events.push(Box::pin(async {
+			let _ = actix_rt::signal::ctrl_c().await;
+			Event::Stop { graceful: false }
+		}));
+		if server.watch_config {
+			events.push(Box::pin(async {
+				interval.tick().await;
+				Event::Poll
+			}));
+		}
+		if resolver.is_some() && server.tls_reload_interval > 0 {
+			events.push(Box::pin(async {
+				tls_interval.tick().await;
+				Event::RefreshCerts
+			}));
+		}
+
+		let (event, _, _) = future::select_all(events).await;
+		match event {
+			Event::Reload => info!("Received SIGHUP, reloading configuration"),
+			Event::Poll => {
+				let current = modified(&config_file);
+				if current == last_modified {
+					continue;
+				}
+				last_modified = current;
+				info!("Configuration file changed, reloading configuration");
+			}
+			Event::RefreshCerts => {
+				if let Some(resolver) = &resolver {
+					resolver.refresh();
+				}
+				continue;
+			}
+			Event::Stop { graceful } => {
+				info!("Stopping server");
+				future::join_all(servers.iter().map(|server| server.stop(graceful))).await;
+				return;
+			}
+		}
+
+		match reload_config(
+			&config_file,
+			&server,
+			&table,
+			resolver.as_deref(),
+			acme.as_ref(),
+		) {
+			Ok(()) => info!("Reloaded configuration"),
+			Err(err) => error!(
+				"Unable to reload config file, keeping previous configuration! {}",
+				err
+			),
+		}
+	}
+}
+
 #[actix_web::main]
 async fn main() {
 	let opts: Opts = Opts::parse();
@@ -67,7 +288,7 @@ async fn main() {
 	let logstr = match opts.verbose - opts.quiet {
 		i32::MIN...-2 => "error",
 		-1 => "warn, actix_web::middleware::logger = info",
-		0 => "info, actix_server::accept = warn, actix_server::builder = warn",
+		0 => "info, actix_server::accept = warn, actix_server::builder = warn, ureq = warn",
 		1 => "debug, actix_server::accept = warn, html5ever = info",
 		2 => "trace, actix_web::middleware::logger = debug, rustls = debug, actix_server::accept = warn, html5ever = info",
 		3...i32::MAX => "trace",
@@ -94,6 +315,8 @@ async fn main() {
 		process::exit(exitcode::CONFIG);
 	});
 
+	let config_file =
+		fs::canonicalize(&opts.config).unwrap_or_else(|_| PathBuf::from(&opts.config));
 	if let Some(config_path) = PathBuf::from(&opts.config).parent() {
 		trace!("setting working directory to {:?}", &config_path);
 		env::set_current_dir(config_path).unwrap_or_else(|err| {
@@ -101,6 +324,11 @@ async fn main() {
 		})
 	}
 
+	if let Some(Command::SelfSigned { hosts }) = &opts.command {
+		generate_self_signed(&config, hosts);
+		return;
+	}
+
 	if config.builder.is_empty()
 		&& config.copier.is_empty()
 		&& config.pre_copier.is_empty()
@@ -138,17 +366,69 @@ async fn main() {
 		}
 	}
 
-	let http_server = http::run_http_server(false, &config.server, &config.headers, &config.vhost)
+	let headers = http::load_header_files(&config.server, &config.headers).unwrap_or_else(|err| {
+		error!("Unable to load header files! {}", err);
+		process::exit(exitcode::CONFIG);
+	});
+
+	let table = http::RoutingTable::new(&config.vhost, &headers).unwrap_or_else(|err| {
+		error!("Unable to configure web server! {}", err);
+		process::exit(exitcode::CONFIG);
+	});
+
+	let challenges = Arc::new(acme::Challenges::default());
+	let resolver = match config.server.tls_bind.is_empty() {
+		true => None,
+		false => {
+			debug!("loading tls certificates");
+			let resolver = tls::CertResolver::new(&config.vhost, &config.server, &challenges)
+				.unwrap_or_else(|err| {
+					error!("Unable to configure HTTPS server! {}", err);
+					process::exit(exitcode::CONFIG);
+				});
+			Some(Arc::new(resolver))
+		}
+	};
+
+	let http_server = http::run_http_server(false, &config.server, &table, None, &challenges)
 		.unwrap_or_else(|err| {
 			error!("Unable to configure HTTP server! {}", err);
 			process::exit(exitcode::CONFIG);
 		});
-	let https_server = http::run_http_server(true, &config.server, &config.headers, &config.vhost)
+	let https_server =
+		http::run_http_server(true, &config.server, &table, resolver.as_ref(), &challenges)
+			.unwrap_or_else(|err| {
+				error!("Unable to configure HTTPS server! {}", err);
+				process::exit(exitcode::CONFIG);
+			});
+
+	let acme = resolver.as_ref().map(|resolver| {
+		acme::Manager::start(
+			&config.server.acme,
+			&config.vhost,
+			resolver.to_owned(),
+			challenges.to_owned(),
+		)
 		.unwrap_or_else(|err| {
-			error!("Unable to configure HTTPS server! {}", err);
+			error!("Unable to start ACME client! {}", err);
 			process::exit(exitcode::CONFIG);
-		});
-	try_join!(http_server, https_server).unwrap_or_else(|err| {
+		})
+	});
+
+	let servers = [http_server, https_server]
+		.iter()
+		.flatten()
+		.cloned()
+		.collect::<Vec<_>>();
+	actix_rt::spawn(handle_events(
+		config_file,
+		table,
+		resolver,
+		acme,
+		config.server.to_owned(),
+		servers.to_owned(),
+	));
+	future::try_join_all(servers).await.unwrap_or_else(|err| {
 		error!("Unable to start server! {}", err);
 		process::exit(exitcode::OSERR);
 	});
@@ -0,0 +1,379 @@
+#![warn(clippy::all)]
+
+use crate::linkchecker;
+use comrak::{
+	arena_tree::Node,
+	nodes::{Ast, AstNode, NodeHtmlBlock, NodeValue},
+	Arena, ComrakOptions,
+};
+use log::{trace, warn};
+use serde_derive::Deserialize;
+use std::{
+	cell::RefCell,
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+const CALLOUTS: &[(&str, &str)] = &[
+	("NOTE", "Note"),
+	("TIP", "Tip"),
+	("IMPORTANT", "Important"),
+	("WARNING", "Warning"),
+	("CAUTION", "Caution"),
+];
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Markdown {
+	#[serde(default)]
+	pub image_attributes: bool,
+
+	#[serde(default)]
+	pub figures: bool,
+
+	#[serde(default)]
+	pub callouts: bool,
+
+	#[serde(default)]
+	pub wiki_links: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WikiTargets {
+	targets: HashMap<String, PathBuf>,
+}
+
+impl WikiTargets {
+	pub fn insert(&mut self, name: &str, url: &Path) {
+		self.targets
+			.entry(name.trim().to_lowercase())
+			.or_insert_with(|| url.to_owned());
+	}
+
+	fn resolve(&self, name: &str) -> Option<&PathBuf> {
+		self.targets.get(&name.trim().to_lowercase())
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct WikiLink {
+	pub target: String,
+	pub url: Option<PathBuf>,
+}
+
+fn escape(input: &str) -> String {
+	input
+		.replace('&', "&amp;")
+		.replace('"', "&quot;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+}
+
+fn collect_text<'a>(node: &'a AstNode<'a>, output: &mut String) {
+	match &node.data.borrow().value {
+		NodeValue::Text(text) | NodeValue::Code(text) => {
+			output.push_str(&String::from_utf8_lossy(text))
+		}
+		NodeValue::SoftBreak | NodeValue::LineBreak => output.push(' '),
+		_ => (),
+	}
+	for child in node.children() {
+		collect_text(child, output);
+	}
+}
+
+fn render_image<'a>(node: &'a AstNode<'a>, markdown: &Markdown, input_dir: &Path) {
+	let (url, title) = match &node.data.borrow().value {
+		NodeValue::Image(link) => (
+			String::from_utf8_lossy(&link.url).to_string(),
+			String::from_utf8_lossy(&link.title).to_string(),
+		),
+		_ => return,
+	};
+	if linkchecker::is_external(&url) {
+		return;
+	}
+
+	let mut alt = String::new();
+	for child in node.children() {
+		collect_text(child, &mut alt);
+	}
+
+	let target = url.split(&['?', '#'][..]).next().unwrap_or_default();
+	let dimensions = linkchecker::resolve_path(input_dir, Path::new(""), target)
+		.and_then(|path| image::image_dimensions(path).ok());
+
+	let figure = match node.parent() {
+		Some(parent) => {
+			markdown.figures
+				&& !title.is_empty()
+				&& matches!(parent.data.borrow().value, NodeValue::Paragraph)
+				&& parent.first_child().is_some_and(|c| c.same_node(node))
+				&& parent.last_child().is_some_and(|c| c.same_node(node))
+		}
+		None => false,
+	};
+
+	trace!("adding attributes to image {:?}", &url);
+	let mut output = [
+		"<img src=\"",
+		&escape(&url),
+		"\" alt=\"",
+		&escape(&alt),
+		"\"",
+	]
+	.concat();
+	if !title.is_empty() && !figure {
+		output.push_str(&[" title=\"", &escape(&title), "\""].concat());
+	}
+	if let Some((width, height)) = dimensions {
+		output.push_str(&format!(" width=\"{}\" height=\"{}\"", width, height));
+	}
+	output.push_str(" loading=\"lazy\" decoding=\"async\" />");
+
+	while let Some(child) = node.first_child() {
+		child.detach();
+	}
+
+	match (figure, node.parent()) {
+		(true, Some(parent)) => {
+			let mut block = NodeHtmlBlock::default();
+			block.literal = [
+				"<figure>",
+				&output,
+				"<figcaption>",
+				&escape(&title),
+				"</figcaption></figure>\n",
+			]
+			.concat()
+			.into_bytes();
+
+			node.detach();
+			parent.data.borrow_mut().value = NodeValue::HtmlBlock(block);
+		}
+		_ => node.data.borrow_mut().value = NodeValue::HtmlInline(output.into_bytes()),
+	}
+}
+
+fn html_block<'a>(arena: &'a Arena<AstNode<'a>>, html: String) -> &'a AstNode<'a> {
+	let mut block = NodeHtmlBlock::default();
+	block.literal = html.into_bytes();
+
+	arena.alloc(Node::new(RefCell::new(Ast::new(NodeValue::HtmlBlock(
+		block,
+	)))))
+}
+
+fn render_callout<'a>(arena: &'a Arena<AstNode<'a>>, node: &'a AstNode<'a>) {
+	let paragraph = match node.first_child() {
+		Some(paragraph) if matches!(paragraph.data.borrow().value, NodeValue::Paragraph) => {
+			paragraph
+		}
+		_ => return,
+	};
+
+	let mut marker = String::new();
+	let mut marker_nodes = Vec::new();
+	for child in paragraph.children() {
+		match &child.data.borrow().value {
+			NodeValue::Text(text) => marker.push_str(&String::from_utf8_lossy(text)),
+			_ => break,
+		}
+		marker_nodes.push(child);
+	}
+
+	let (kind, title) = match marker.trim_start().strip_prefix("[!") {
+		Some(marker) => match marker.split_once(']') {
+			Some((kind, title)) => (kind.to_ascii_uppercase(), title.trim().to_owned()),
+			None => return,
+		},
+		None => return,
+	};
+	let (kind, default_title) = match CALLOUTS.iter().find(|(name, _)| *name == kind) {
+		Some(callout) => callout,
+		None => return,
+	};
+	let title = match title.is_empty() {
+		true => default_title.to_string(),
+		false => title,
+	};
+
+	trace!("rendering {} callout", kind);
+	for child in marker_nodes {
+		child.detach();
+	}
+	if let Some(child) = paragraph.first_child() {
+		if matches!(
+			child.data.borrow().value,
+			NodeValue::SoftBreak | NodeValue::LineBreak
+		) {
+			child.detach();
+		}
+	}
+	if paragraph.first_child().is_none() {
+		paragraph.detach();
+	}
+
+	node.insert_before(html_block(
+		arena,
+		[
+			"<aside class=\"callout callout-",
+			&kind.to_ascii_lowercase(),
+			"\" role=note>\n<p class=callout-title>",
+			&escape(&title),
+			"</p>\n",
+		]
+		.concat(),
+	));
+	while let Some(child) = node.first_child() {
+		node.insert_before(child);
+	}
+	node.insert_before(html_block(arena, "</aside>\n".to_string()));
+	node.detach();
+}
+
+fn render_wiki_links<'a>(
+	arena: &'a Arena<AstNode<'a>>,
+	node: &'a AstNode<'a>,
+	targets: &WikiTargets,
+) -> Vec<WikiLink> {
+	let text = match &node.data.borrow().value {
+		NodeValue::Text(text) => String::from_utf8_lossy(text).to_string(),
+		_ => return vec![],
+	};
+	if node
+		.ancestors()
+		.any(|ancestor| matches!(ancestor.data.borrow().value, NodeValue::Link(_)))
+	{
+		return vec![];
+	}
+
+	let mut links = Vec::new();
+	let mut rest = text.as_str();
+	let mut replaced = false;
+	while let Some(start) = rest.find("[[") {
+		let end = match rest[start..].find("]]") {
+			Some(end) => start + end,
+			None => break,
+		};
+
+		let inner = &rest[start + 2..end];
+		let (target, label) = match inner.split_once('|') {
+			Some((target, label)) => (target.trim(), label.trim()),
+			None => (inner.trim(), inner.trim()),
+		};
+		let (name, fragment) = match target.split_once('#') {
+			Some((name, fragment)) => (name, Some(fragment)),
+			None => (target, None),
+		};
+
+		let url = targets.resolve(name).cloned();
+		let html = match &url {
+			Some(url) => {
+				let mut href = url.to_string_lossy().to_string();
+				if let Some(fragment) = fragment {
+					href = [&href, "#", fragment].concat();
+				}
+				[
+					"<a href=\"",
+					&escape(&href),
+					"\" class=wiki-link>",
+					&escape(label),
+					"</a>",
+				]
+				.concat()
+			}
+			None => escape(&rest[start..end + 2]),
+		};
+		links.push(WikiLink {
+			target: target.to_owned(),
+			url,
+		});
+
+		if start > 0 {
+			node.insert_before(arena.alloc(Node::new(RefCell::new(Ast::new(NodeValue::Text(
+				rest.as_bytes()[..start].to_vec(),
+			))))));
+		}
+		node.insert_before(
+			arena.alloc(Node::new(RefCell::new(Ast::new(NodeValue::HtmlInline(
+				html.into_bytes(),
+			))))),
+		);
+		rest = &rest[end + 2..];
+		replaced = true;
+	}
+
+	if replaced {
+		match rest.is_empty() {
+			true => node.detach(),
+			false => node.data.borrow_mut().value = NodeValue::Text(rest.as_bytes().to_vec()),
+		}
+	}
+
+	links
+}
+
+pub fn render_markdown(
+	input: &str,
+	markdown: &Markdown,
+	sanitize: bool,
+	input_dir: &Path,
+	targets: &WikiTargets,
+) -> (String, Vec<WikiLink>) {
+	let mut options = ComrakOptions::default();
+	options.extension.strikethrough = true;
+	options.extension.table = true;
+	options.extension.autolink = true;
+	options.extension.tasklist = true;
+	options.extension.superscript = true;
+	if !sanitize {
+		options.extension.header_ids = Some("".to_string());
+		options.extension.footnotes = true;
+	}
+	options.extension.description_lists = true;
+	options.extension.front_matter_delimiter = Some("---".to_string());
+	options.parse.smart = true;
+	options.render.unsafe_ = true;
+
+	let arena = Arena::new();
+	let root = comrak::parse_document(&arena, input, &options);
+
+	if markdown.image_attributes {
+		let images = root
+			.descendants()
+			.filter(|node| matches!(node.data.borrow().value, NodeValue::Image(_)))
+			.collect::<Vec<_>>();
+		for image in images {
+			render_image(image, markdown, input_dir);
+		}
+	}
+
+	if markdown.callouts {
+		let blockquotes = root
+			.descendants()
+			.filter(|node| matches!(node.data.borrow().value, NodeValue::BlockQuote))
+			.collect::<Vec<_>>();
+		for blockquote in blockquotes {
+			render_callout(&arena, blockquote);
+		}
+	}
+
+	let mut links = Vec::new();
+	if markdown.wiki_links {
+		let texts = root
+			.descendants()
+			.filter(|node| matches!(node.data.borrow().value, NodeValue::Text(_)))
+			.collect::<Vec<_>>();
+		for text in texts {
+			links.extend(render_wiki_links(&arena, text, targets));
+		}
+	}
+
+	let mut output = Vec::new();
+	if let Err(err) = comrak::format_html(root, &options, &mut output) {
+		warn!("Unable to render Markdown! {}", err);
+	}
+
+	(String::from_utf8_lossy(&output).to_string(), links)
+}
@@ -0,0 +1,492 @@
+#![warn(clippy::all)]
+
+use liquid::Object;
+use liquid_core::{
+	parser::{FilterArguments, ParameterReflection},
+	Error as LiquidError, Expression, Filter, FilterReflection, ParseFilter, Runtime, Value,
+	ValueView,
+};
+use log::{debug, error, info, trace, warn};
+use serde_derive::Deserialize;
+use std::{
+	collections::HashSet,
+	fmt, fs,
+	path::PathBuf,
+	sync::{Arc, Mutex},
+};
+use wasmi::{
+	Caller, Config, Engine, Extern, Instance, Linker, Module, Store, StoreLimits,
+	StoreLimitsBuilder,
+};
+
+pub const ABI_VERSION: i32 = 1;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+	pub path: PathBuf,
+
+	#[serde(default = "default_plugin_fuel")]
+	pub fuel: u64,
+
+	#[serde(default = "default_plugin_max_memory")]
+	pub max_memory: usize,
+
+	#[serde(default)]
+	pub config: Object,
+}
+
+fn default_plugin_fuel() -> u64 {
+	1_000_000_000
+}
+
+fn default_plugin_max_memory() -> usize {
+	64 * 1024 * 1024
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+	#[serde(default)]
+	pub transforms: Vec<Transform>,
+
+	#[serde(default)]
+	pub html_transforms: Vec<String>,
+
+	#[serde(default)]
+	pub filters: Vec<Registration>,
+
+	#[serde(default)]
+	pub data: Vec<Registration>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Transform {
+	pub extension: String,
+	pub output_extension: Option<String>,
+	pub function: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Registration {
+	pub name: String,
+	pub function: String,
+}
+
+struct HostState {
+	name: String,
+	limits: StoreLimits,
+	error: Option<String>,
+}
+
+pub struct Plugin {
+	pub name: String,
+	pub manifest: Manifest,
+	config: PluginConfig,
+	engine: Engine,
+	module: Module,
+	instance: Mutex<Option<(Store<HostState>, Instance)>>,
+}
+
+impl fmt::Debug for Plugin {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Plugin")
+			.field("name", &self.name)
+			.field("manifest", &self.manifest)
+			.finish()
+	}
+}
+
+fn read_memory(memory: &[u8], ptr: u32, len: u32) -> Option<&[u8]> {
+	let start = ptr as usize;
+	memory.get(start..start.checked_add(len as usize)?)
+}
+
+fn log_message(caller: Caller<'_, HostState>, level: i32, ptr: i32, len: i32) {
+	let memory = match caller.get_export("memory").and_then(Extern::into_memory) {
+		Some(memory) => memory,
+		None => return,
+	};
+	let message = read_memory(memory.data(&caller), ptr as u32, len as u32)
+		.map(String::from_utf8_lossy)
+		.unwrap_or_default();
+
+	let name = &caller.data().name;
+	match level {
+		0 => error!("[{}] {}", name, message),
+		1 => warn!("[{}] {}", name, message),
+		2 => info!("[{}] {}", name, message),
+		3 => debug!("[{}] {}", name, message),
+		_ => trace!("[{}] {}", name, message),
+	}
+}
+
+fn fail(mut caller: Caller<'_, HostState>, ptr: i32, len: i32) {
+	let message = caller
+		.get_export("memory")
+		.and_then(Extern::into_memory)
+		.and_then(|memory| {
+			read_memory(memory.data(&caller), ptr as u32, len as u32)
+				.map(|message| String::from_utf8_lossy(message).to_string())
+		})
+		.unwrap_or_default();
+
+	caller.data_mut().error = Some(message);
+}
+
+impl Plugin {
+	pub fn load(config: &PluginConfig) -> Result<Plugin, String> {
+		debug!("loading plugin {:?}", &config.path);
+
+		let wasm = fs::read(&config.path).map_err(|err| err.to_string())?;
+
+		let mut engine_config = Config::default();
+		engine_config.consume_fuel(true);
+		let engine = Engine::new(&engine_config);
+		let module = Module::new(&engine, &wasm).map_err(|err| err.to_string())?;
+
+		let mut plugin = Plugin {
+			name: config
+				.path
+				.file_stem()
+				.unwrap_or_default()
+				.to_string_lossy()
+				.to_string(),
+			manifest: Manifest::default(),
+			config: config.to_owned(),
+			engine,
+			module,
+			instance: Mutex::new(None),
+		};
+
+		let (mut store, instance) = plugin.instantiate()?;
+		let version = instance
+			.get_typed_func::<(), i32>(&store, "katwebsite_abi_version")
+			.and_then(|func| func.call(&mut store, ()))
+			.map_err(|err| format!("katwebsite_abi_version: {}", err))?;
+		if version != ABI_VERSION {
+			return Err(format!(
+				"plugin uses ABI version {}, expected {}",
+				version, ABI_VERSION
+			));
+		}
+		plugin.instance = Mutex::new(Some((store, instance)));
+
+		let manifest = plugin.call("katwebsite_manifest", None)?;
+		plugin.manifest = serde_json::from_slice(&manifest)
+			.map_err(|err| format!("unable to parse manifest: {}", err))?;
+		trace!(
+			"plugin {:?} registered {:?}",
+			&plugin.name,
+			&plugin.manifest
+		);
+
+		Ok(plugin)
+	}
+
+	fn instantiate(&self) -> Result<(Store<HostState>, Instance), String> {
+		let mut store = Store::new(
+			&self.engine,
+			HostState {
+				name: self.name.to_owned(),
+				limits: StoreLimitsBuilder::new()
+					.memory_size(self.config.max_memory)
+					.build(),
+				error: None,
+			},
+		);
+		store.limiter(|state| &mut state.limits);
+		store
+			.set_fuel(self.config.fuel)
+			.map_err(|err| err.to_string())?;
+
+		let mut linker = <Linker<HostState>>::new(&self.engine);
+		linker
+			.func_wrap("katwebsite", "log", log_message)
+			.map_err(|err| err.to_string())?;
+		linker
+			.func_wrap("katwebsite", "fail", fail)
+			.map_err(|err| err.to_string())?;
+
+		let instance = linker
+			.instantiate(&mut store, &self.module)
+			.and_then(|instance| instance.start(&mut store))
+			.map_err(|err| err.to_string())?;
+
+		Ok((store, instance))
+	}
+
+	pub fn call(&self, function: &str, input: Option<&[u8]>) -> Result<Vec<u8>, String> {
+		trace!("calling {:?} in plugin {:?}", function, &self.name);
+
+		// The instance is reused for every call during a build, and only
+		// replaced after a call fails, as it may have been left in a broken state.
+		let mut cached = match self.instance.lock() {
+			Ok(cached) => cached,
+			Err(err) => err.into_inner(),
+		};
+		let (mut store, instance) = match cached.take() {
+			Some(cached) => cached,
+			None => self.instantiate()?,
+		};
+		store
+			.set_fuel(self.config.fuel)
+			.map_err(|err| err.to_string())?;
+
+		let output = Self::call_instance(&mut store, &instance, function, input);
+		if output.is_ok() {
+			*cached = Some((store, instance));
+		}
+
+		output
+	}
+
+	fn call_instance(
+		store: &mut Store<HostState>,
+		instance: &Instance,
+		function: &str,
+		input: Option<&[u8]>,
+	) -> Result<Vec<u8>, String> {
+		let memory = instance
+			.get_memory(&*store, "memory")
+			.ok_or("plugin does not export memory")?;
+
+		let packed = match input {
+			Some(input) => {
+				let ptr = instance
+					.get_typed_func::<i32, i32>(&*store, "katwebsite_alloc")
+					.and_then(|alloc| alloc.call(&mut *store, input.len() as i32))
+					.map_err(|err| format!("katwebsite_alloc: {}", err))?;
+				memory
+					.write(&mut *store, ptr as u32 as usize, input)
+					.map_err(|err| format!("katwebsite_alloc: {}", err))?;
+
+				instance
+					.get_typed_func::<(i32, i32), i64>(&*store, function)
+					.and_then(|func| func.call(&mut *store, (ptr, input.len() as i32)))
+			}
+			None => instance
+				.get_typed_func::<(), i64>(&*store, function)
+				.and_then(|func| func.call(&mut *store, ())),
+		}
+		.map_err(|err| format!("{}: {}", function, err))?;
+
+		if let Some(err) = store.data_mut().error.take() {
+			return Err(format!("{}: {}", function, err));
+		}
+
+		let (ptr, len) = ((packed as u64 >> 32) as u32, packed as u32);
+		read_memory(memory.data(&*store), ptr, len)
+			.map(|output| output.to_vec())
+			.ok_or_else(|| format!("{}: returned an out of bounds string", function))
+	}
+
+	pub fn call_str(&self, function: &str, input: &str) -> Result<String, String> {
+		let output = self.call(function, Some(input.as_bytes()))?;
+		String::from_utf8(output).map_err(|err| format!("{}: {}", function, err))
+	}
+
+	pub fn load_data(&self) -> Vec<Object> {
+		let config = serde_json::to_vec(&self.config.config).unwrap_or_default();
+
+		self.manifest
+			.data
+			.iter()
+			.filter_map(|loader| {
+				trace!(
+					"loading data {:?} from plugin {:?}",
+					&loader.name,
+					&self.name
+				);
+
+				let output = self
+					.call(&loader.function, Some(&config))
+					.and_then(|output| {
+						serde_json::from_slice(&output).map_err(|err| err.to_string())
+					});
+				match output {
+					Ok(data) => Some(data),
+					Err(err) => {
+						warn!(
+							"Unable to load data {:?} from plugin {:?}! {}",
+							&loader.name, &self.name, err
+						);
+						None
+					}
+				}
+			})
+			.collect()
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Plugins {
+	plugins: Vec<Arc<Plugin>>,
+}
+
+impl Plugins {
+	pub fn load(configs: &[PluginConfig], builtin_filters: &[&str]) -> Result<Plugins, String> {
+		let plugins = configs
+			.iter()
+			.map(|config| {
+				Plugin::load(config)
+					.map(Arc::new)
+					.map_err(|err| format!("{:?}: {}", &config.path, err))
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		let mut filters = HashSet::new();
+		for plugin in &plugins {
+			for filter in &plugin.manifest.filters {
+				if builtin_filters.contains(&filter.name.as_str()) {
+					return Err(format!(
+						"{:?}: filter {:?} conflicts with a built-in filter",
+						&plugin.config.path, &filter.name
+					));
+				}
+				if !filters.insert(filter.name.as_str()) {
+					return Err(format!(
+						"{:?}: filter {:?} conflicts with a filter from another plugin",
+						&plugin.config.path, &filter.name
+					));
+				}
+			}
+		}
+
+		Ok(Plugins { plugins })
+	}
+
+	pub fn transform(&self, extension: &str) -> Option<(&Plugin, &Transform)> {
+		self.plugins.iter().find_map(|plugin| {
+			plugin
+				.manifest
+				.transforms
+				.iter()
+				.find(|transform| transform.extension == extension)
+				.map(|transform| (plugin.as_ref(), transform))
+		})
+	}
+
+	pub fn html_transforms(&self) -> impl Iterator<Item = (&Plugin, &String)> {
+		self.plugins.iter().flat_map(|plugin| {
+			plugin
+				.manifest
+				.html_transforms
+				.iter()
+				.map(move |function| (plugin.as_ref(), function))
+		})
+	}
+
+	pub fn filters(&self) -> Vec<PluginFilter> {
+		self.plugins
+			.iter()
+			.flat_map(|plugin| {
+				plugin
+					.manifest
+					.filters
+					.iter()
+					.map(move |filter| PluginFilter {
+						name: filter.name.to_owned(),
+						function: filter.function.to_owned(),
+						plugin: plugin.to_owned(),
+					})
+			})
+			.collect()
+	}
+
+	pub fn load_data(&self) -> Vec<Object> {
+		self.plugins
+			.iter()
+			.flat_map(|plugin| plugin.load_data())
+			.collect()
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct PluginFilter {
+	name: String,
+	function: String,
+	plugin: Arc<Plugin>,
+}
+
+impl FilterReflection for PluginFilter {
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn description(&self) -> &str {
+		"A filter provided by a WebAssembly plugin."
+	}
+
+	fn positional_parameters(&self) -> &'static [ParameterReflection] {
+		&[]
+	}
+
+	fn keyword_parameters(&self) -> &'static [ParameterReflection] {
+		&[]
+	}
+}
+
+impl ParseFilter for PluginFilter {
+	fn parse(&self, arguments: FilterArguments) -> liquid_core::Result<Box<dyn Filter>> {
+		Ok(Box::new(ParsedPluginFilter {
+			filter: self.to_owned(),
+			positional: arguments.positional.collect(),
+			keyword: arguments
+				.keyword
+				.map(|(key, value)| (key.to_owned(), value))
+				.collect(),
+		}))
+	}
+
+	fn reflection(&self) -> &dyn FilterReflection {
+		self
+	}
+}
+
+#[derive(Debug)]
+struct ParsedPluginFilter {
+	filter: PluginFilter,
+	positional: Vec<Expression>,
+	keyword: Vec<(String, Expression)>,
+}
+
+impl fmt::Display for ParsedPluginFilter {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", &self.filter.name)
+	}
+}
+
+impl Filter for ParsedPluginFilter {
+	fn evaluate(&self, input: &dyn ValueView, runtime: &dyn Runtime) -> liquid_core::Result<Value> {
+		let mut args = Vec::new();
+		for arg in &self.positional {
+			args.push(arg.evaluate(runtime)?.to_value());
+		}
+		let mut options = Object::new();
+		for (key, arg) in &self.keyword {
+			options.insert(key.to_owned().into(), arg.evaluate(runtime)?.to_value());
+		}
+
+		let request = liquid::object!({
+			"input": input.to_value(),
+			"args": args,
+			"options": options,
+		});
+		let output = serde_json::to_vec(&request)
+			.map_err(|err| err.to_string())
+			.and_then(|request| {
+				self.filter
+					.plugin
+					.call(&self.filter.function, Some(&request))
+			})
+			.and_then(|output| serde_json::from_slice(&output).map_err(|err| err.to_string()))
+			.map_err(|err| {
+				LiquidError::with_msg(err).context("plugin", self.filter.plugin.name.to_owned())
+			})?;
+
+		Ok(output)
+	}
+}
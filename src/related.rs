@@ -0,0 +1,147 @@
+#![warn(clippy::all)]
+
+use crate::html::{self, Token};
+use liquid::{model::Value, Object};
+use rayon::prelude::*;
+use serde_derive::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Related {
+	#[serde(default = "default_related_limit")]
+	pub limit: usize,
+
+	#[serde(default = "default_related_taxonomies")]
+	pub taxonomies: Vec<String>,
+
+	#[serde(default)]
+	pub content_similarity: bool,
+}
+
+fn default_related_limit() -> usize {
+	5
+}
+
+fn default_related_taxonomies() -> Vec<String> {
+	vec!["tags".to_string(), "categories".to_string()]
+}
+
+impl Default for Related {
+	fn default() -> Self {
+		Related {
+			limit: default_related_limit(),
+			taxonomies: default_related_taxonomies(),
+			content_similarity: false,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Document {
+	terms: HashSet<(String, String)>,
+	words: HashMap<String, f64>,
+}
+
+impl Related {
+	pub fn document(&self, data: &Object, content: &str) -> Document {
+		let mut document = Document::default();
+
+		for taxonomy in &self.taxonomies {
+			let values = match data.get(taxonomy.as_str()) {
+				Some(Value::Array(values)) => values.to_owned(),
+				Some(value @ Value::Scalar(_)) => vec![value.to_owned()],
+				_ => continue,
+			};
+			document
+				.terms
+				.extend(values.iter().filter_map(|value| match value {
+					Value::Scalar(term) => Some((
+						taxonomy.to_owned(),
+						term.to_owned().into_string().to_lowercase(),
+					)),
+					_ => None,
+				}));
+		}
+
+		if self.content_similarity {
+			let mut ignored = 0;
+			for token in html::tokenize(content) {
+				match token {
+					Token::Start { name, .. }
+						if html::IGNORED_ELEMENTS.contains(&name.as_str()) =>
+					{
+						ignored += 1
+					}
+					Token::End { name } if html::IGNORED_ELEMENTS.contains(&name.as_str()) => {
+						ignored = usize::saturating_sub(ignored, 1)
+					}
+					Token::Text(text) if ignored == 0 => {
+						for word in text
+							.split(|c: char| !c.is_alphanumeric())
+							.filter(|word| word.chars().count() > 2)
+						{
+							*document.words.entry(word.to_lowercase()).or_default() += 1.0;
+						}
+					}
+					_ => (),
+				}
+			}
+		}
+
+		document
+	}
+
+	pub fn rank(&self, documents: &[Document]) -> Vec<Vec<usize>> {
+		let mut frequency: HashMap<&str, f64> = HashMap::new();
+		for document in documents {
+			for word in document.words.keys() {
+				*frequency.entry(word).or_default() += 1.0;
+			}
+		}
+
+		let count = documents.len() as f64;
+		let vectors = documents
+			.par_iter()
+			.map(|document| {
+				let mut vector = document
+					.words
+					.iter()
+					.map(|(word, tf)| (word.as_str(), tf * (count / frequency[word.as_str()]).ln()))
+					.filter(|(_, weight)| *weight > 0.0)
+					.collect::<HashMap<_, _>>();
+
+				let norm = vector.values().map(|w| w * w).sum::<f64>().sqrt();
+				if norm > 0.0 {
+					vector.values_mut().for_each(|w| *w /= norm);
+				}
+				vector
+			})
+			.collect::<Vec<_>>();
+
+		documents
+			.par_iter()
+			.enumerate()
+			.map(|(index, document)| {
+				let mut scores = documents
+					.iter()
+					.enumerate()
+					.filter(|(other, _)| *other != index)
+					.map(|(other, other_document)| {
+						let shared = document.terms.intersection(&other_document.terms).count();
+						let similarity = vectors[index]
+							.iter()
+							.filter_map(|(word, w)| Some(w * vectors[other].get(word)?))
+							.sum::<f64>();
+						(other, shared as f64 + similarity)
+					})
+					.filter(|(_, score)| *score > 0.0)
+					.collect::<Vec<_>>();
+
+				scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+				scores.truncate(self.limit);
+				scores.into_iter().map(|(other, _)| other).collect()
+			})
+			.collect()
+	}
+}
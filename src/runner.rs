@@ -1,8 +1,12 @@
 #![warn(clippy::all)]
 
-use log::{error, info};
+use log::{error, info, trace};
 use serde_derive::Deserialize;
-use std::process::Command;
+use std::{
+	io::Write,
+	process::{Command, Stdio},
+	thread,
+};
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Runner {
@@ -12,6 +16,68 @@ pub struct Runner {
 	pub args: Vec<String>,
 }
 
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ExternalCommand {
+	pub extension: String,
+
+	pub command: String,
+
+	#[serde(default)]
+	pub args: Vec<String>,
+
+	pub output_extension: Option<String>,
+
+	#[serde(default = "default_command_liquid")]
+	pub liquid: bool,
+
+	#[serde(default = "default_command_layout")]
+	pub layout: bool,
+}
+
+fn default_command_liquid() -> bool {
+	true
+}
+
+fn default_command_layout() -> bool {
+	true
+}
+
+pub fn run_pipe(command: &ExternalCommand, input: &str) -> Result<String, String> {
+	trace!(
+		"piping to {:?} with args {:?}",
+		&command.command,
+		&command.args
+	);
+
+	let mut child = Command::new(&command.command)
+		.args(&command.args)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.spawn()
+		.map_err(|err| format!("unable to run {:?}: {}", &command.command, err))?;
+
+	let mut stdin = child.stdin.take().ok_or("unable to open stdin")?;
+	let input = input.to_owned();
+	let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+	let output = child.wait_with_output().map_err(|err| err.to_string())?;
+	if let Ok(Err(err)) = writer.join() {
+		return Err(format!(
+			"unable to write to {:?}: {}",
+			&command.command, err
+		));
+	}
+	if !output.status.success() {
+		return Err(format!(
+			"command {:?} exited with {}",
+			&command.command, output.status
+		));
+	}
+
+	String::from_utf8(output.stdout).map_err(|err| err.to_string())
+}
+
 pub fn run_runner(runner: &Runner) -> bool {
 	if runner.args.is_empty() {
 		info!("Running {:?}", &runner.command);
@@ -0,0 +1,195 @@
+#![warn(clippy::all)]
+
+use crate::{acme::write_private, hosts::HostPattern};
+use log::{debug, info, warn};
+use rcgen::{
+	BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+	KeyPair, KeyUsagePurpose, PKCS_ECDSA_P256_SHA256,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{
+	error::Error,
+	fs,
+	path::{Path, PathBuf},
+};
+use time::{Duration, OffsetDateTime};
+use x509_parser::prelude::{ASN1Time, FromDer, GeneralName, X509Certificate};
+
+const CA_NAME: &str = "KatWebSite Development CA";
+const CA_DAYS: i64 = 3650;
+const CERT_DAYS: i64 = 365;
+const RENEW_DAYS: i64 = 30;
+const LOCAL_SUFFIXES: &[&str] = &[".localhost", ".test", ".local", ".internal", ".example"];
+
+pub fn ca_file(dir: &Path) -> PathBuf {
+	dir.join("ca.pem")
+}
+
+pub fn pemfiles(dir: &Path, host: &str) -> Vec<PathBuf> {
+	let dir = dir.join(host.replace('*', "_"));
+	vec![dir.join("cert.pem"), dir.join("key.pem")]
+}
+
+fn is_local(host: &str) -> bool {
+	host == "localhost" || LOCAL_SUFFIXES.iter().any(|suffix| host.ends_with(suffix))
+}
+
+fn read_cert(path: &Path) -> Option<Vec<u8>> {
+	let data = fs::read(path).ok()?;
+	pem::parse(data).ok().map(|block| block.contents().to_vec())
+}
+
+struct Ca {
+	cert: Certificate,
+	subject: Vec<u8>,
+	pem: String,
+}
+
+fn ca_params(name: &str, key: KeyPair) -> CertificateParams {
+	let mut params = CertificateParams::default();
+	params.distinguished_name.push(DnType::CommonName, name);
+	params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+	params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+	params.alg = &PKCS_ECDSA_P256_SHA256;
+	params.key_pair = Some(key);
+	params
+}
+
+fn load_ca(dir: &Path) -> Result<Ca, Box<dyn Error>> {
+	let cert_file = ca_file(dir);
+	let key_file = dir.join("ca-key.pem");
+
+	if let (Some(der), Ok(key)) = (read_cert(&cert_file), fs::read_to_string(&key_file)) {
+		debug!(
+			"loading self-signed certificate authority from {:?}",
+			cert_file
+		);
+		let (_, parsed) = X509Certificate::from_der(&der)
+			.map_err(|err| format!("{:?}: unable to parse certificate: {}", cert_file, err))?;
+		let name = parsed
+			.subject()
+			.iter_common_name()
+			.next()
+			.and_then(|name| name.as_str().ok())
+			.ok_or_else(|| format!("{:?}: certificate has no common name", cert_file))?
+			.to_owned();
+		let key = KeyPair::from_pem(&key).map_err(|err| format!("{:?}: {}", key_file, err))?;
+		return Ok(Ca {
+			cert: Certificate::from_params(ca_params(&name, key))?,
+			subject: parsed.subject().as_raw().to_vec(),
+			pem: fs::read_to_string(&cert_file)?,
+		});
+	}
+
+	// Each CA gets a unique name, so certificates issued by a previous CA can be told apart.
+	let mut id = [0; 4];
+	SystemRandom::new()
+		.fill(&mut id)
+		.map_err(|_| "unable to generate random ca name")?;
+	let name = format!("{} {}", CA_NAME, hex(&id));
+	info!("Generating self-signed certificate authority {:?}", name);
+
+	let mut params = ca_params(&name, KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?);
+	let now = OffsetDateTime::now_utc();
+	params.not_before = now - Duration::days(1);
+	params.not_after = now + Duration::days(CA_DAYS);
+	let cert = Certificate::from_params(params)?;
+	let der = cert.serialize_der()?;
+	let pem = pem::encode(&pem::Pem::new("CERTIFICATE", der.to_owned()));
+
+	fs::create_dir_all(dir)?;
+	write_private(&key_file, &cert.serialize_private_key_pem())?;
+	fs::write(&cert_file, &pem)?;
+
+	let (_, parsed) = X509Certificate::from_der(&der)
+		.map_err(|err| format!("unable to parse generated certificate: {}", err))?;
+	Ok(Ca {
+		subject: parsed.subject().as_raw().to_vec(),
+		cert,
+		pem,
+	})
+}
+
+fn hex(data: &[u8]) -> String {
+	data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn is_current(path: &Path, ca_subject: &[u8], names: &[String]) -> bool {
+	let der = match read_cert(path) {
+		Some(der) => der,
+		None => return false,
+	};
+	let cert = match X509Certificate::from_der(&der) {
+		Ok((_, cert)) => cert,
+		Err(_) => return false,
+	};
+
+	let sans = match cert.subject_alternative_name() {
+		Ok(Some(sans)) => sans
+			.value
+			.general_names
+			.iter()
+			.filter_map(|name| match name {
+				GeneralName::DNSName(name) => Some(name.to_string()),
+				_ => None,
+			})
+			.collect(),
+		_ => Vec::new(),
+	};
+
+	let renew_at = ASN1Time::now().timestamp() + RENEW_DAYS * 86400;
+	cert.issuer().as_raw() == ca_subject
+		&& cert.validity().not_after.timestamp() > renew_at
+		&& sans == names
+}
+
+pub fn generate(dir: &Path, names: &[String]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+	let host = names.first().ok_or("no host names")?;
+	let mut sans = Vec::new();
+	for name in names {
+		match HostPattern::parse(name)? {
+			HostPattern::Exact(name) => sans.push(name),
+			HostPattern::Wildcard(suffix) => sans.push(["*.", &suffix].concat()),
+			HostPattern::Regex(_) => {
+				return Err(format!("certificates can't be generated for {:?}", name).into())
+			}
+		}
+	}
+
+	let pemfiles = pemfiles(dir, host);
+	let ca = load_ca(dir)?;
+	if is_current(&pemfiles[0], &ca.subject, &sans) && pemfiles[1].exists() {
+		debug!("self-signed certificate for {:?} is up to date", host);
+		return Ok(pemfiles);
+	}
+
+	for name in sans.iter().filter(|name| !is_local(name)) {
+		warn!(
+			"Generating a self-signed certificate for {:?}, which isn't a local host name! It will only be trusted by clients which trust {:?}.",
+			name,
+			ca_file(dir)
+		);
+	}
+	info!("Generating self-signed certificate for {:?}", host);
+
+	let mut params = CertificateParams::new(sans);
+	params.distinguished_name.push(DnType::CommonName, host);
+	params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+	params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+	params.alg = &PKCS_ECDSA_P256_SHA256;
+	let now = OffsetDateTime::now_utc();
+	params.not_before = now - Duration::days(1);
+	params.not_after = now + Duration::days(CERT_DAYS);
+	let cert = Certificate::from_params(params)?;
+
+	if let Some(parent) = pemfiles[0].parent() {
+		fs::create_dir_all(parent)?;
+	}
+	write_private(&pemfiles[1], &cert.serialize_private_key_pem())?;
+	fs::write(
+		&pemfiles[0],
+		[cert.serialize_pem_with_signer(&ca.cert)?, ca.pem].concat(),
+	)?;
+
+	Ok(pemfiles)
+}
@@ -0,0 +1,206 @@
+#![warn(clippy::all)]
+
+use liquid::{model::Value, Object};
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+
+pub type Templates = BTreeMap<String, String>;
+
+const OPEN: &str = "{{<";
+const CLOSE: &str = ">}}";
+const ESCAPED_OPEN: &str = "{{</*";
+const ESCAPED_CLOSE: &str = "*/>}}";
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Shortcode {
+	pub name: String,
+	pub args: Vec<String>,
+	pub params: Object,
+}
+
+fn split_args(input: &str) -> Vec<String> {
+	let mut args = Vec::new();
+	let mut current = String::new();
+	let mut quote = None;
+	let mut started = false;
+
+	for c in input.chars() {
+		match (quote, c) {
+			(Some(q), c) if c == q => quote = None,
+			(Some(_), c) => current.push(c),
+			(None, '"') | (None, '\'') => {
+				quote = Some(c);
+				started = true;
+			}
+			(None, c) if c.is_whitespace() => {
+				if started {
+					args.push(std::mem::take(&mut current));
+					started = false;
+				}
+			}
+			(None, c) => {
+				current.push(c);
+				started = true;
+			}
+		}
+	}
+	if started {
+		args.push(current);
+	}
+
+	args
+}
+
+fn parse(input: &str) -> Option<Shortcode> {
+	let mut args = split_args(input).into_iter();
+	let name = args.next()?;
+
+	let mut shortcode = Shortcode {
+		name,
+		args: Vec::new(),
+		params: Object::new(),
+	};
+	for arg in args {
+		match arg.split_once('=') {
+			Some((key, value)) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+				shortcode
+					.params
+					.insert(key.to_owned().into(), Value::scalar(value.to_owned()));
+			}
+			_ => shortcode.args.push(arg),
+		}
+	}
+
+	Some(shortcode)
+}
+
+fn placeholder(index: usize) -> String {
+	format!("KWSSHORTCODE{}END", index)
+}
+
+pub fn extract<F>(input: &str, mut render: F) -> Result<(String, Vec<String>), String>
+where
+	F: FnMut(&Shortcode) -> Result<String, String>,
+{
+	let mut output = String::new();
+	let mut rendered = Vec::new();
+
+	let mut rest = input;
+	while let Some(start) = rest.find(OPEN) {
+		if rest[start..].starts_with(ESCAPED_OPEN) {
+			if let Some(end) = rest[start..].find(ESCAPED_CLOSE) {
+				let end = start + end;
+				output.push_str(&rest[..start]);
+				output.push_str(&placeholder(rendered.len()));
+				rendered.push(
+					[OPEN, &rest[start + ESCAPED_OPEN.len()..end], CLOSE]
+						.concat()
+						.replace('&', "&amp;")
+						.replace('<', "&lt;")
+						.replace('>', "&gt;"),
+				);
+				rest = &rest[end + ESCAPED_CLOSE.len()..];
+				continue;
+			}
+		}
+
+		let end = match rest[start..].find(CLOSE) {
+			Some(end) => start + end,
+			None => break,
+		};
+
+		let shortcode = parse(&rest[start + OPEN.len()..end])
+			.ok_or_else(|| "shortcode is missing a name".to_string())?;
+
+		output.push_str(&rest[..start]);
+		output.push_str(&placeholder(rendered.len()));
+		rendered.push(render(&shortcode)?);
+		rest = &rest[end + CLOSE.len()..];
+	}
+	output.push_str(rest);
+
+	Ok((output, rendered))
+}
+
+pub fn restore(input: &str, rendered: &[String]) -> String {
+	let mut output = input.to_owned();
+	for (index, content) in rendered.iter().enumerate() {
+		let placeholder = placeholder(index);
+		output = output
+			.replace(&["<p>", &placeholder, "</p>"].concat(), content)
+			.replace(&placeholder, content);
+	}
+
+	output
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn render_names(input: &str) -> Result<(String, Vec<String>), String> {
+		extract(input, |shortcode| Ok(shortcode.name.to_owned()))
+	}
+
+	#[test]
+	fn quoted_arguments_keep_whitespace() {
+		let mut shortcodes = Vec::new();
+		let (output, rendered) = extract(
+			r#"Watch {{< youtube dQw4w9WgXcQ 'two words' title="My video" empty="" >}} now"#,
+			|shortcode| {
+				shortcodes.push(shortcode.to_owned());
+				Ok("<iframe>".to_string())
+			},
+		)
+		.unwrap();
+
+		assert_eq!(output, "Watch KWSSHORTCODE0END now");
+		assert_eq!(rendered, vec!["<iframe>"]);
+		assert_eq!(shortcodes[0].name, "youtube");
+		assert_eq!(shortcodes[0].args, vec!["dQw4w9WgXcQ", "two words"]);
+		assert_eq!(
+			shortcodes[0].params.get("title"),
+			Some(&Value::scalar("My video"))
+		);
+		assert_eq!(shortcodes[0].params.get("empty"), Some(&Value::scalar("")));
+	}
+
+	#[test]
+	fn escaped_shortcodes_are_written_as_text() {
+		let (output, rendered) = extract("{{</* youtube <id> */>}} {{< note >}}", |shortcode| {
+			assert_eq!(shortcode.name, "note");
+			Ok("note".to_string())
+		})
+		.unwrap();
+
+		assert_eq!(output, "KWSSHORTCODE0END KWSSHORTCODE1END");
+		assert_eq!(rendered, vec!["{{&lt; youtube &lt;id&gt; &gt;}}", "note"]);
+	}
+
+	#[test]
+	fn unterminated_shortcodes_are_left_alone() {
+		let (output, rendered) = render_names("{{</* escaped {{< open").unwrap();
+
+		assert_eq!(output, "{{</* escaped {{< open");
+		assert!(rendered.is_empty());
+	}
+
+	#[test]
+	fn shortcodes_without_a_name_are_rejected() {
+		assert!(render_names("{{< >}}").is_err());
+		assert!(render_names("text {{<>}}").is_err());
+	}
+
+	#[test]
+	fn restore_removes_wrapping_paragraphs() {
+		let rendered = vec!["<div></div>".to_string(), "b".to_string()];
+
+		assert_eq!(
+			restore(
+				"<p>KWSSHORTCODE0END</p>\n<p>a KWSSHORTCODE1END</p>",
+				&rendered
+			),
+			"<div></div>\n<p>a b</p>"
+		);
+	}
+}